@@ -4,13 +4,21 @@ use std::{
     path::PathBuf,
 };
 
-use artspace_core::sampler::DdimSampler;
+use artspace_core::{
+    merge::MergeMode,
+    model::Search,
+    sampler::DdimSampler,
+    tile::{TileConfig, Tiling},
+};
 use clap::{Parser, Subcommand};
 use ndarray::{Axis, Slice};
 use nshare::ToNdarray3;
 use tauri::api::path;
 
-use crate::{model_manager::ModelManager, pipeline::Pipeline};
+use crate::{
+    model_manager::ModelManager,
+    pipeline::{Pipeline, Settings},
+};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -72,16 +80,144 @@ enum Commands {
         seed: Option<PathBuf>,
         #[clap(long)]
         seed_strength: Option<f32>,
+
+        /// Denoise wide images as overlapping windows of the native size
+        #[clap(long)]
+        panorama: bool,
+
+        /// Generate a texture that repeats without seams
+        #[clap(long)]
+        tileable: bool,
+
+        #[clap(long, requires_all = ["control_path", "control_hint"])]
+        control_kind: Option<String>,
+        #[clap(long)]
+        control_path: Option<PathBuf>,
+        /// Hint image for the control model, e.g. a pose or edge map
+        #[clap(long)]
+        control_hint: Option<PathBuf>,
+        #[clap(long, default_value_t = 1.0)]
+        control_strength: f32,
+        /// Fraction of the sampling steps the control is active for, e.g. 0.0:0.8
+        #[clap(long, default_value = "0.0:1.0")]
+        control_range: String,
+
+        /// Condition on the depth of the seed image
+        #[clap(long, requires_all = ["depth_path", "seed"])]
+        depth_kind: Option<String>,
+        #[clap(long)]
+        depth_path: Option<PathBuf>,
+
+        /// Generate variations of this image instead of following the text
+        #[clap(long, requires = "image_encoder_path")]
+        variation: Option<PathBuf>,
+        #[clap(long, default_value = "clip")]
+        image_encoder_kind: String,
+        #[clap(long)]
+        image_encoder_path: Option<PathBuf>,
+
+        /// Generate this many candidates and keep the ones closest to the text
+        #[clap(long, default_value_t = 1, requires_all = ["image_encoder_path", "score_text_path"])]
+        best_of: usize,
+        #[clap(long, default_value_t = 1)]
+        keep: usize,
+        /// CLIP text encoder used for scoring candidates
+        #[clap(long)]
+        score_text_path: Option<PathBuf>,
+        /// Aesthetic predictor head added to the candidate scores
+        #[clap(long)]
+        aesthetic_path: Option<PathBuf>,
+
+        /// Textual inversion embeddings, used in the text by their token, e.g. <my-style>
+        #[clap(long)]
+        embedding: Vec<PathBuf>,
+
+        /// LoRA file to merge into the diffusion model, as path[:strength];
+        /// switches to the fp16 UNet, since int8 weights can't take LoRAs
+        #[clap(long)]
+        lora: Vec<PathBuf>,
+
+        /// Upscale with this model instead of the pipeline's default
+        #[clap(long, requires = "sr_path")]
+        sr_kind: Option<String>,
+        #[clap(long)]
+        sr_path: Option<PathBuf>,
+
+        /// Check the output with a safety checker
+        #[clap(long)]
+        safety_path: Option<PathBuf>,
+        /// What to do with flagged images
+        #[clap(long, default_value = "blur", value_parser = ["blur", "black-out", "report"])]
+        safety_action: String,
     },
     AutoEncoder {
         kind: String,
         path: PathBuf,
         input: PathBuf,
         output: PathBuf,
+
+        /// Encode and decode in tiles of this many pixels
+        #[clap(long)]
+        tile: Option<usize>,
+    },
+    RestoreFaces {
+        detector_kind: String,
+        detector_path: PathBuf,
+        kind: String,
+        path: PathBuf,
+        input: PathBuf,
+        output: PathBuf,
+
+        /// Trade quality for fidelity to the input, for models that support it
+        #[clap(long)]
+        fidelity: Option<f32>,
+    },
+    /// Suggest a prompt for an image
+    Interrogate {
+        kind: String,
+        path: PathBuf,
+        input: PathBuf,
+
+        /// Beam search width, 1 for greedy decoding
+        #[clap(long, default_value_t = 3)]
+        beams: usize,
+    },
+    /// Merge tsar models of the same architecture into a new tsar model
+    Merge {
+        output: PathBuf,
+        #[clap(required = true, num_args = 2..=3)]
+        inputs: Vec<PathBuf>,
+
+        /// Weight of the second model, or of the difference of the second and third
+        #[clap(long, default_value_t = 0.5)]
+        alpha: f32,
+
+        /// Add the difference of the second and third models to the first
+        #[clap(long)]
+        add_difference: bool,
+    },
+    /// Print the kind of a model, which loaders also accept as "auto"
+    DetectKind { path: PathBuf },
+    /// Run a pipeline with ORT profiling and print the time of each stage and node
+    Profile {
+        kind: String,
+        text: String,
+
+        /// Use the fp16 diffusion model instead of the int8 one
+        #[clap(long)]
+        fp16: bool,
+
+        /// Where ORT writes its traces
+        #[clap(long)]
+        dir: Option<PathBuf>,
+
+        /// How many of the slowest operators and nodes to print per model
+        #[clap(long, default_value_t = 10)]
+        top: usize,
     },
 }
 
-pub async fn exec() -> bool {
+pub async fn exec(settings: &Settings) -> bool {
     let cli = Cli::parse();
     match &cli.command {
         Some(Commands::TextEncode {
@@ -91,7 +227,12 @@ pub async fn exec() -> bool {
             text,
             output,
         }) => {
-            let mut m = artspace_core::model::load_text_encoder(kind, path).unwrap();
+            let mut m = artspace_core::model::load_text_encoder(
+                kind,
+                path,
+                &settings.session_options(false),
+            )
+            .unwrap();
             let out = {
                 let enc = text
                     .iter()
@@ -135,9 +276,16 @@ pub async fn exec() -> bool {
             width,
             height,
         }) => {
-            let mut sr = artspace_core::model::load_super_resolution(sr_kind, sr_path).unwrap();
+            let mut sr = artspace_core::model::load_super_resolution(
+                sr_kind,
+                sr_path,
+                &settings.session_options(false),
+            )
+            .unwrap();
 
-            let mut m = artspace_core::model::load_diffusion(kind, path).unwrap();
+            let mut m =
+                artspace_core::model::load_diffusion(kind, path, &settings.session_options(true))
+                    .unwrap();
             let cond: ndarray::ArrayD<f32> = ndarray_npy::read_npy(cond_path).unwrap();
             let clip_cond: ndarray::ArrayD<f32> = ndarray_npy::read_npy(clip_cond_path).unwrap();
             let noise = m.make_noise(
@@ -184,8 +332,12 @@ pub async fn exec() -> bool {
             };
 
             let image = {
-                let mut m =
-                    artspace_core::model::load_auto_encoder(decoder_kind, decoder_path).unwrap();
+                let mut m = artspace_core::model::load_auto_encoder(
+                    decoder_kind,
+                    decoder_path,
+                    &settings.session_options(false),
+                )
+                .unwrap();
                 m.decode(&d).unwrap()
             };
             let image = { sr.execute(&image).unwrap() };
@@ -218,6 +370,7 @@ pub async fn exec() -> bool {
             path,
             input,
             output,
+            tile,
         }) => {
             let img = image::io::Reader::open(input)
                 .unwrap()
@@ -243,7 +396,18 @@ pub async fn exec() -> bool {
                 .to_owned()
                 .into_dyn();
 
-            let mut ae = artspace_core::model::load_auto_encoder(kind, path).unwrap();
+            let mut ae = artspace_core::model::load_auto_encoder(
+                kind,
+                path,
+                &settings.session_options(false),
+            )
+            .unwrap();
+            if let Some(t) = tile {
+                ae.set_tiling(Tiling::Enabled(TileConfig {
+                    tile_size: *t,
+                    overlap: *t / 8,
+                }));
+            }
 
             let e = ae.encode(&img).unwrap();
             let img = ae.decode(&e).unwrap();
@@ -251,6 +415,156 @@ pub async fn exec() -> bool {
             let mut out = std::fs::File::create(output).unwrap();
             out.write_all(&Pipeline::get_png(&img)).unwrap();
         }
+        Some(Commands::RestoreFaces {
+            detector_kind,
+            detector_path,
+            kind,
+            path,
+            input,
+            output,
+            fidelity,
+        }) => {
+            let img = Pipeline::open_image(input).unwrap();
+
+            let mut detector = artspace_core::model::load_face_detector(
+                detector_kind,
+                detector_path,
+                &settings.session_options(false),
+            )
+            .unwrap();
+            let mut m = artspace_core::model::load_face_restoration(
+                kind,
+                path,
+                &settings.session_options(false),
+            )
+            .unwrap();
+            if let Some(f) = fidelity {
+                m.set_fidelity(*f);
+            }
+
+            let img =
+                artspace_core::face::restore_faces(&img, detector.as_mut(), m.as_mut(), |i, n| {
+                    println!("Restoring face {}/{}", (i + 1).min(n), n)
+                })
+                .unwrap();
+
+            let mut out = std::fs::File::create(output).unwrap();
+            out.write_all(&Pipeline::get_png(&img)).unwrap();
+        }
+        Some(Commands::Interrogate {
+            kind,
+            path,
+            input,
+            beams,
+        }) => {
+            let mut m =
+                artspace_core::model::load_captioner(kind, path, &settings.session_options(false))
+                    .unwrap();
+            let search = if *beams > 1 {
+                Search::Beam { width: *beams }
+            } else {
+                Search::Greedy
+            };
+            println!(
+                "{}",
+                Pipeline::interrogate(m.as_mut(), input, search).unwrap()
+            );
+        }
+        Some(Commands::Merge {
+            output,
+            inputs,
+            alpha,
+            add_difference,
+        }) => {
+            let mode = if *add_difference {
+                MergeMode::AddDifference { alpha: *alpha }
+            } else {
+                MergeMode::WeightedSum { alpha: *alpha }
+            };
+            artspace_core::merge::merge_models(inputs, mode, output, |i, n| {
+                println!("Merging {}/{}", i, n)
+            })
+            .unwrap();
+        }
+        Some(Commands::DetectKind { path }) => {
+            println!("{}", artspace_core::model::detect_kind(path).unwrap());
+        }
+        Some(Commands::Profile {
+            kind,
+            text,
+            fp16,
+            dir,
+            top,
+        }) => {
+            use std::time::Instant;
+
+            let dir = dir
+                .clone()
+                .unwrap_or_else(|| std::env::temp_dir().join("artspace-profile"));
+            // every model the pipeline loads is profiled
+            let settings = Settings {
+                profile_dir: Some(dir),
+                use_fp16: settings.use_fp16 || *fp16,
+                ..settings.clone()
+            };
+            let mm = ModelManager::new(
+                path::data_dir()
+                    .unwrap_or_else(|| "./".into())
+                    .join("artspace/models"),
+            )
+            .unwrap();
+
+            // sessions load lazily, so each stage includes loading its models
+            let mut stages = vec![];
+            let now = Instant::now();
+            let mut p = Pipeline::new(kind, &mm, &settings, |p| println!("{}", p))
+                .await
+                .unwrap();
+            stages.push(("setup", now.elapsed()));
+            let now = Instant::now();
+            p.step_text(text).await.unwrap();
+            stages.push(("text", now.elapsed()));
+            let now = Instant::now();
+            let img = p
+                .step_diffuse(1., 1., None, |p| println!("{}", p))
+                .await
+                .unwrap();
+            stages.push(("diffuse", now.elapsed()));
+            let now = Instant::now();
+            p.step_post_process(&img, |p| println!("{}", p))
+                .await
+                .unwrap();
+            stages.push(("post process", now.elapsed()));
+            // dropping the sessions ends their profiles
+            drop(p);
+
+            println!("Stages:");
+            for (name, t) in stages {
+                println!("  {:<16} {:>10.2?}", name, t);
+            }
+            for profile in artspace_core::ort::take_profiles() {
+                let total: std::time::Duration = profile.runs.iter().sum();
+                println!(
+                    "{}: {} runs, {:.2?} total, {:.2?} per run ({})",
+                    profile.model,
+                    profile.runs.len(),
+                    total,
+                    total / profile.runs.len().max(1) as u32,
+                    profile.file.display()
+                );
+                println!("  Operators:");
+                for (op, calls, t) in profile.op_types().iter().take(*top) {
+                    println!("    {:<24} {:>6} calls {:>10.2?}", op, calls, t);
+                }
+                println!("  Nodes:");
+                for n in profile.nodes.iter().take(*top) {
+                    println!(
+                        "    {:<48} {:<16} {:>10.2?} {}",
+                        n.name, n.op_type, n.total, n.provider
+                    );
+                }
+            }
+        }
         Some(Commands::Pipeline {
             kind,
             text,
@@ -260,6 +574,35 @@ pub async fn exec() -> bool {
 
             seed,
             seed_strength,
+            panorama,
+            tileable,
+
+            control_kind,
+            control_path,
+            control_hint,
+            control_strength,
+            control_range,
+
+            depth_kind,
+            depth_path,
+
+            variation,
+            image_encoder_kind,
+            image_encoder_path,
+
+            best_of,
+            keep,
+            score_text_path,
+            aesthetic_path,
+
+            embedding,
+            lora,
+
+            sr_kind,
+            sr_path,
+
+            safety_path,
+            safety_action,
         }) => {
             let mm = ModelManager::new(
                 path::data_dir()
@@ -268,24 +611,170 @@ pub async fn exec() -> bool {
             )
             .unwrap();
 
-            let mut p = Pipeline::new(kind, &mm, |p| println!("{}", p))
+            let mut p = Pipeline::new(kind, &mm, settings, |p| println!("{}", p))
                 .await
                 .unwrap();
-            p.step_text(text).await.unwrap();
+            p.set_panorama(*panorama);
+            p.set_tileable(*tileable);
+            for e in embedding {
+                let tokens = p.add_embeddings(e).unwrap();
+                println!("Loaded embeddings {}", tokens.join(", "));
+            }
+            if !lora.is_empty() {
+                let loras = lora
+                    .iter()
+                    .map(|l| {
+                        let spec = l.to_string_lossy();
+                        match spec.rsplit_once(':').map(|(f, s)| (f, s.parse::<f32>())) {
+                            Some((f, Ok(strength))) => (PathBuf::from(f), strength),
+                            _ => (l.clone(), 1.0),
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                p.set_loras(&loras, &mm, |p| println!("{}", p))
+                    .await
+                    .unwrap();
+            }
+            if let Some(path) = sr_path {
+                p.set_super_resolution(
+                    artspace_core::model::load_super_resolution(
+                        sr_kind.as_deref().unwrap_or("esrgan"),
+                        path,
+                        &settings.session_options(false),
+                    )
+                    .unwrap(),
+                );
+            }
+            if let Some(path) = safety_path {
+                p.set_safety_checker(
+                    artspace_core::model::load_safety_checker(
+                        "clip/safety-checker",
+                        path,
+                        &settings.session_options(false),
+                    )
+                    .unwrap(),
+                    safety_action.parse().unwrap(),
+                );
+            }
+            if let (Some(kind), Some(path), Some(hint)) = (control_kind, control_path, control_hint)
+            {
+                let (start, end) = control_range
+                    .split_once(':')
+                    .and_then(|(s, e)| Some((s.parse().ok()?, e.parse().ok()?)))
+                    .expect("invalid control range");
+                p.add_control(
+                    artspace_core::model::load_control_net(
+                        kind,
+                        path,
+                        &settings.session_options(true),
+                    )
+                    .unwrap(),
+                    p.open_seed(hint).unwrap(),
+                    *control_strength,
+                    (start, end),
+                )
+                .unwrap();
+            }
+            if let (Some(img), Some(path)) = (variation, image_encoder_path) {
+                let mut m = artspace_core::model::load_image_encoder(
+                    image_encoder_kind,
+                    path,
+                    &settings.session_options(false),
+                )
+                .unwrap();
+                let img = p.open_seed(img).unwrap();
+                p.step_image(m.as_mut(), &img, text).await.unwrap();
+            } else {
+                p.step_text(text).await.unwrap();
+            }
 
             let seed = seed
                 .as_ref()
                 .map(|f| (p.open_seed(f).unwrap(), seed_strength.unwrap_or(0.5)));
 
-            let img = p
-                .step_diffuse(width.unwrap_or(1.), height.unwrap_or(1.), seed, |p| {
-                    println!("{}", p)
-                })
-                .await
+            if let (Some(kind), Some(path), Some((img, _))) = (depth_kind, depth_path, &seed) {
+                let mut m = artspace_core::model::load_depth_estimator(
+                    kind,
+                    path,
+                    &settings.session_options(false),
+                )
                 .unwrap();
+                p.set_depth(m.as_mut(), img).unwrap();
+            }
 
-            let mut out = std::fs::File::create(output).unwrap();
-            out.write_all(&Pipeline::get_png(&img)).unwrap();
+            let mut images = vec![];
+            for i in 0..(*best_of).max(1) {
+                if *best_of > 1 {
+                    println!("Candidate {}/{}", i + 1, best_of);
+                }
+                let img = p
+                    .step_diffuse(
+                        width.unwrap_or(1.),
+                        height.unwrap_or(1.),
+                        seed.clone(),
+                        |p| println!("{}", p),
+                    )
+                    .await
+                    .unwrap();
+                if p.flagged().iter().any(|f| *f) {
+                    println!("Image flagged by safety checker");
+                }
+                images.push(img);
+            }
+
+            if let (true, Some(image_path), Some(text_path)) =
+                (*best_of > 1, image_encoder_path, score_text_path)
+            {
+                let mut scorer = artspace_core::model::ClipScorer::new(
+                    artspace_core::model::load_image_encoder(
+                        image_encoder_kind,
+                        image_path,
+                        &settings.session_options(false),
+                    )
+                    .unwrap(),
+                    artspace_core::model::load_text_encoder(
+                        "clip",
+                        text_path,
+                        &settings.session_options(false),
+                    )
+                    .unwrap(),
+                );
+                if let Some(path) = aesthetic_path {
+                    scorer = scorer.with_aesthetic(
+                        artspace_core::model::load_aesthetic_predictor(
+                            "clip/aesthetic",
+                            path,
+                            &settings.session_options(false),
+                        )
+                        .unwrap(),
+                    );
+                }
+                images = Pipeline::select_best(&mut scorer, images, text, *keep)
+                    .unwrap()
+                    .into_iter()
+                    .map(|(img, score)| {
+                        println!("Score {:?}", score);
+                        img
+                    })
+                    .collect();
+            } else {
+                images.truncate(*keep);
+            }
+
+            for (i, img) in images.iter().enumerate() {
+                let path = if images.len() > 1 {
+                    output.with_file_name(format!(
+                        "{}-{}.{}",
+                        output.file_stem().unwrap_or_default().to_string_lossy(),
+                        i,
+                        output.extension().unwrap_or_default().to_string_lossy()
+                    ))
+                } else {
+                    output.clone()
+                };
+                let mut out = std::fs::File::create(path).unwrap();
+                out.write_all(&Pipeline::get_png(img)).unwrap();
+            }
         }
         None => return false,
     }
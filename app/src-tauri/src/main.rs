@@ -10,20 +10,76 @@ use async_std::path::PathBuf;
 use lazy_static::lazy_static;
 use tauri::api::path;
 
-use crate::pipeline::Pipeline;
+use crate::pipeline::{Pipeline, Settings};
 
 mod cli;
 mod model_manager;
 mod pipeline;
 
 lazy_static! {
+    static ref CONFIG: Config = Config::from_env();
     static ref CURRENT_STATUS: Mutex<String> = Mutex::new(String::from(""));
     static ref PIPELINE: async_std::sync::Mutex<Option<Pipeline>> =
         async_std::sync::Mutex::new(None);
-    static ref RESULTS: async_std::sync::Mutex<Vec<ndarray::ArrayD<f32>>> =
+    static ref RESULTS: async_std::sync::Mutex<Vec<Generated>> =
         async_std::sync::Mutex::new(vec![]);
 }
 
+// A generated image and whether the safety checker flagged it, last checked
+// when it was diffused or post processed.
+#[derive(Default)]
+struct Generated {
+    image: ndarray::ArrayD<f32>,
+    flagged: Vec<bool>,
+}
+
+// Everything the app takes from the environment, read in this one place.
+struct Config {
+    settings: Settings,
+    // path and action, e.g. SAFETY_CHECKER_PATH=... SAFETY_ACTION=blur on shared machines
+    safety_checker: Option<(std::path::PathBuf, String)>,
+    // kind and path, e.g. SUPER_RESOLUTION_KIND=ldm/upscaler for prompt-guided upscaling
+    super_resolution: Option<(String, std::path::PathBuf)>,
+    // detector path, restoration kind and path
+    face_restoration: Option<(std::path::PathBuf, String, std::path::PathBuf)>,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        let var = |k: &str| std::env::var(k).ok();
+        Self {
+            settings: Settings {
+                disable_coreml: var("DISABLE_COREML").is_some(),
+                use_cuda: var("USE_CUDA").is_some(),
+                use_fp16: var("USE_FP16").is_some(),
+                profile_dir: var("ORT_PROFILE_DIR").map(Into::into),
+                memory_budget_mb: var("MEMORY_BUDGET_MB").and_then(|mb| match mb.parse() {
+                    Ok(mb) => Some(mb),
+                    Err(_) => {
+                        println!("Invalid MEMORY_BUDGET_MB: {}", mb);
+                        None
+                    }
+                }),
+            },
+            safety_checker: var("SAFETY_CHECKER_PATH").map(|path| {
+                let action = var("SAFETY_ACTION").unwrap_or_else(|| "blur".to_string());
+                (path.into(), action)
+            }),
+            super_resolution: var("SUPER_RESOLUTION_PATH").map(|path| {
+                let kind = var("SUPER_RESOLUTION_KIND").unwrap_or_else(|| "esrgan".to_string());
+                (kind, path.into())
+            }),
+            face_restoration: match (var("FACE_DETECTOR_PATH"), var("FACE_RESTORATION_PATH")) {
+                (Some(detector), Some(path)) => {
+                    let kind = var("FACE_RESTORATION_KIND").unwrap_or_else(|| "gfpgan".to_string());
+                    Some((detector.into(), kind, path.into()))
+                }
+                _ => None,
+            },
+        }
+    }
+}
+
 #[tauri::command]
 fn get_status() -> String {
     CURRENT_STATUS.lock().unwrap().clone()
@@ -63,8 +119,40 @@ async fn init(kind: String) -> Option<bool> {
 
         let _ = mm.cleanup().await;
 
-        let e = (Pipeline::new(&kind, &mm, log)).await;
-        *p = Some(set_error(e)?);
+        let settings = &CONFIG.settings;
+        let e = (Pipeline::new(&kind, &mm, settings, log)).await;
+        let mut pipeline = set_error(e)?;
+        if let Some((path, action)) = &CONFIG.safety_checker {
+            let action = set_error(action.parse())?;
+            let checker = set_error(artspace_core::model::load_safety_checker(
+                "clip/safety-checker",
+                path,
+                &settings.session_options(false),
+            ))?;
+            pipeline.set_safety_checker(checker, action);
+        }
+        if let Some((kind, path)) = &CONFIG.super_resolution {
+            let sr = set_error(artspace_core::model::load_super_resolution(
+                kind,
+                path,
+                &settings.session_options(false),
+            ))?;
+            pipeline.set_super_resolution(sr);
+        }
+        if let Some((detector, kind, path)) = &CONFIG.face_restoration {
+            let detector = set_error(artspace_core::model::load_face_detector(
+                "retinaface",
+                detector,
+                &settings.session_options(false),
+            ))?;
+            let restoration = set_error(artspace_core::model::load_face_restoration(
+                kind,
+                path,
+                &settings.session_options(false),
+            ))?;
+            pipeline.set_face_restoration(detector, restoration);
+        }
+        *p = Some(pipeline);
     }
     Some(true)
 }
@@ -81,27 +169,55 @@ async fn step_text(text: String) -> Option<bool> {
 #[tauri::command]
 async fn step_diffuse(w: f32, h: f32, idx: usize) -> Option<Vec<u8>> {
     let mut p = PIPELINE.lock().await;
-    let img = set_error(p.as_mut().unwrap().step_diffuse(w, h, None, log).await)?;
+    let p = p.as_mut().unwrap();
+    let img = set_error(p.step_diffuse(w, h, None, log).await)?;
     let png = Pipeline::get_png(&img);
     let mut result = RESULTS.lock().await;
     if result.len() <= idx {
-        result.resize(idx + 1, ndarray::ArrayD::zeros(ndarray::IxDyn(&[])));
+        result.resize_with(idx + 1, Generated::default);
     }
-    result[idx] = img;
+    result[idx] = Generated {
+        image: img,
+        flagged: p.flagged().to_vec(),
+    };
 
     Some(png)
 }
 
+#[tauri::command]
+async fn interrogate(kind: String, path: String, image: String) -> Option<String> {
+    log("Interrogating image...");
+    let mut m = set_error(artspace_core::model::load_captioner(
+        kind,
+        path,
+        &CONFIG.settings.session_options(false),
+    ))?;
+    set_error(Pipeline::interrogate(
+        m.as_mut(),
+        image,
+        artspace_core::model::Search::Beam { width: 3 },
+    ))
+}
+
+#[tauri::command]
+async fn get_flagged(idx: usize) -> Vec<bool> {
+    RESULTS
+        .lock()
+        .await
+        .get(idx)
+        .map(|g| g.flagged.clone())
+        .unwrap_or_default()
+}
+
 #[tauri::command]
 async fn step_post(idx: usize, path: String) -> Option<()> {
     log("Processing image...");
     let mut p = PIPELINE.lock().await;
-    let img = set_error(
-        p.as_mut()
-            .unwrap()
-            .step_post_process(&RESULTS.lock().await[idx])
-            .await,
-    )?;
+    let p = p.as_mut().unwrap();
+    let mut results = RESULTS.lock().await;
+    let img = set_error(p.step_post_process(&results[idx].image, log).await)?;
+    results[idx].flagged = p.flagged().to_vec();
+    drop(results);
 
     log("Saving image...");
     let mut out = std::fs::File::create(PathBuf::from(path)).unwrap();
@@ -115,7 +231,8 @@ fn log(s: impl Into<String>) {
 
 #[tokio::main]
 async fn main() {
-    if !cli::exec().await {
+    pipeline::init_memory_budget(&CONFIG.settings);
+    if !cli::exec(&CONFIG.settings).await {
         #[allow(unused_imports)]
         tauri::Builder::default()
             .invoke_handler(tauri::generate_handler![
@@ -124,6 +241,8 @@ async fn main() {
                 init,
                 step_text,
                 step_diffuse,
+                get_flagged,
+                interrogate,
                 step_post
             ])
             .run(tauri::tauri_build_context!())
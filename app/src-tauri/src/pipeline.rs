@@ -1,16 +1,105 @@
 use std::{
     collections::HashMap,
-    path::Path,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 
 use anyhow::Result;
-use artspace_core::sampler::LmsSampler;
+use artspace_core::{
+    face, imgproc,
+    model::{
+        normalize_depth, Captioner, ClipScorer, Control, ControlNet, DepthEstimator, Diffusion,
+        FaceDetector, FaceRestoration, ImageEncoder, SafetyAction, SafetyChecker, Score, Search,
+    },
+    ort::{self, ExecutionProvider, SessionOptions},
+    sampler::{LmsSampler, MultiDiffusion, SeamlessDiffusion},
+    tile::TileConfig,
+};
 use ndarray::{Axis, Slice};
 use nshare::ToNdarray3;
 
 use crate::model_manager::ModelManager;
 
+const SR_TILE: TileConfig = TileConfig {
+    tile_size: 256,
+    overlap: 32,
+};
+
+// Budget when the system memory is unknown, enough for the unet and the
+// text encoder of the large pipeline but not for every model at once.
+const FALLBACK_MEMORY_BUDGET: usize = 4 << 30;
+
+// How the pipeline runs its models. main settles these once and passes them
+// down, rather than every loader reading the environment.
+#[derive(Clone, Debug, Default)]
+pub struct Settings {
+    pub disable_coreml: bool,
+    // CUDA for the models that run on every sampling step
+    pub use_cuda: bool,
+    // fp16 diffusion models instead of the int8 ones
+    pub use_fp16: bool,
+    // where ORT writes the traces of every session
+    pub profile_dir: Option<PathBuf>,
+    // weights of loaded models in MB, with 0 for no limit and None for half of
+    // the system memory; memory used while running comes on top
+    pub memory_budget_mb: Option<usize>,
+}
+
+impl Settings {
+    // `gpu` is for the models that run on every sampling step.
+    pub fn session_options(&self, gpu: bool) -> SessionOptions {
+        let mut options = SessionOptions::default().with_optimized_model_cache(true);
+        if self.disable_coreml {
+            options = options.with_providers(vec![]);
+        }
+        if gpu && self.use_cuda {
+            options = options.with_provider(ExecutionProvider::Cuda { device_id: 0 });
+        }
+        if let Some(dir) = &self.profile_dir {
+            options = options.with_profiling(dir);
+        }
+        options
+    }
+}
+
+// Limits the weights of loaded models so models not used for a while are unloaded.
+pub fn init_memory_budget(settings: &Settings) {
+    let budget = match settings.memory_budget_mb {
+        Some(0) => None,
+        Some(mb) => Some(mb << 20),
+        None => Some(system_memory().map_or(FALLBACK_MEMORY_BUDGET, |m| m / 2)),
+    };
+    ort::set_memory_budget(budget);
+}
+
+#[cfg(target_os = "macos")]
+fn system_memory() -> Option<usize> {
+    let out = std::process::Command::new("sysctl")
+        .args(["-n", "hw.memsize"])
+        .output()
+        .ok()?;
+    String::from_utf8(out.stdout).ok()?.trim().parse().ok()
+}
+
+#[cfg(target_os = "linux")]
+fn system_memory() -> Option<usize> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let kb = meminfo
+        .lines()
+        .find_map(|l| l.strip_prefix("MemTotal:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<usize>()
+        .ok()?;
+    Some(kb << 10)
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+fn system_memory() -> Option<usize> {
+    None
+}
+
 struct TextEncoder {
     model: Arc<Mutex<Box<dyn artspace_core::model::TextEncoder>>>,
     key: String,
@@ -19,16 +108,31 @@ struct TextEncoder {
 pub struct Pipeline {
     text_encoders: Vec<TextEncoder>,
     diffuse: Box<dyn artspace_core::model::Diffusion>,
+    // fp16 unet to switch to for LoRAs, while the loaded one is quantized
+    diffuse_fp16: Option<&'static str>,
+    settings: Settings,
     autoencoder: Box<dyn artspace_core::model::AutoEncoder>,
     diffuse_output_size: (usize, usize),
     steps: usize,
     sr: Option<Box<dyn artspace_core::model::SuperResolution>>,
+    sr_tile: TileConfig,
+    face: Option<(Box<dyn FaceDetector>, Box<dyn FaceRestoration>)>,
+    panorama: bool,
+    tileable: bool,
+    depth: Option<ndarray::ArrayD<f32>>,
+    safety: Option<(Box<dyn SafetyChecker>, SafetyAction)>,
+    flagged: Vec<bool>,
 
     text_embedding: Option<Vec<ndarray::ArrayD<f32>>>,
 }
 
 impl Pipeline {
-    pub async fn new(kind: &str, mm: &ModelManager, progress: impl Fn(String)) -> Result<Self> {
+    pub async fn new(
+        kind: &str,
+        mm: &ModelManager,
+        settings: &Settings,
+        progress: impl Fn(String),
+    ) -> Result<Self> {
         if kind == "small" {
             Ok(Self {
                 text_encoders: vec![
@@ -36,6 +140,7 @@ impl Pipeline {
                         model: Arc::new(Mutex::new(artspace_core::model::load_text_encoder(
                             "clip",
                             mm.download("clip/vit-l-14.tsar", &progress).await?,
+                            &settings.session_options(false),
                         )?)),
                         key: "clip".to_string(),
                     },
@@ -44,30 +149,47 @@ impl Pipeline {
                             "ldm/bert",
                             mm.download("ldm/text2img-large/bert.int8.tsar", &progress)
                                 .await?,
+                            &settings.session_options(false),
                         )?)),
                         key: "c".to_string(),
                     },
                 ],
                 diffuse: artspace_core::model::load_diffusion(
                     "ldm/ldm",
-                    if std::env::var("USE_FP16").is_ok() {
+                    if settings.use_fp16 {
                         mm.download("ldm/glid-3-xl/ldm.fp16.tsar", &progress)
                             .await?
                     } else {
                         mm.download("ldm/glid-3-xl/ldm.int8.tsar", &progress)
                             .await?
                     },
+                    &settings.session_options(true),
                 )?,
+                diffuse_fp16: if settings.use_fp16 {
+                    None
+                } else {
+                    Some("ldm/glid-3-xl/ldm.fp16.tsar")
+                },
+                settings: settings.clone(),
                 diffuse_output_size: (256, 32),
                 autoencoder: artspace_core::model::load_auto_encoder(
                     "ldm/vq",
                     mm.download("ldm/text2img-large/vq.tsar", &progress).await?,
+                    &settings.session_options(false),
                 )?,
                 steps: 45,
                 sr: Some(artspace_core::model::load_super_resolution(
                     "esrgan",
                     mm.download("esrgan/x4plus.tsar", &progress).await?,
+                    &settings.session_options(false),
                 )?),
+                sr_tile: SR_TILE,
+                face: None,
+                panorama: false,
+                tileable: false,
+                depth: None,
+                safety: None,
+                flagged: vec![],
 
                 text_embedding: None,
             })
@@ -77,26 +199,42 @@ impl Pipeline {
                     model: Arc::new(Mutex::new(artspace_core::model::load_text_encoder(
                         "clip-pos",
                         mm.download("clip/vit-l-14.tsar", &progress).await?,
+                        &settings.session_options(false),
                     )?)),
                     key: "c".to_string(),
                 }],
                 diffuse: artspace_core::model::load_diffusion(
                     "ldm/ldm",
-                    if std::env::var("USE_FP16").is_ok() {
+                    if settings.use_fp16 {
                         mm.download("stable-diffusion/unet.fp16.tsar", &progress)
                             .await?
                     } else {
                         mm.download("stable-diffusion/unet.int8.tsar", &progress)
                             .await?
                     },
+                    &settings.session_options(true),
                 )?,
+                diffuse_fp16: if settings.use_fp16 {
+                    None
+                } else {
+                    Some("stable-diffusion/unet.fp16.tsar")
+                },
+                settings: settings.clone(),
                 diffuse_output_size: (512, 64),
                 autoencoder: artspace_core::model::load_auto_encoder(
                     "ldm/vq",
                     mm.download("stable-diffusion/vae.tsar", &progress).await?,
+                    &settings.session_options(false),
                 )?,
                 steps: 45,
                 sr: None,
+                sr_tile: SR_TILE,
+                face: None,
+                panorama: false,
+                tileable: false,
+                depth: None,
+                safety: None,
+                flagged: vec![],
 
                 text_embedding: None,
             })
@@ -109,6 +247,125 @@ impl Pipeline {
         ["small", "large"].iter().map(|s| s.to_string()).collect()
     }
 
+    pub fn set_panorama(&mut self, panorama: bool) {
+        self.panorama = panorama;
+    }
+
+    pub fn set_tileable(&mut self, tileable: bool) {
+        self.tileable = tileable;
+        self.autoencoder.set_seamless(tileable);
+    }
+
+    // Loads textual inversion embeddings into every text encoder that supports them.
+    pub fn add_embeddings(&mut self, path: impl AsRef<Path>) -> Result<Vec<String>> {
+        let results: Vec<_> = self
+            .text_encoders
+            .iter()
+            .map(|e| e.model.lock().unwrap().add_embeddings(path.as_ref()))
+            .collect();
+        let mut err = None;
+        for r in results {
+            match r {
+                Ok(tokens) => return Ok(tokens),
+                Err(e) => {
+                    err.get_or_insert(e);
+                }
+            }
+        }
+        Err(err.map_or_else(
+            || anyhow::anyhow!("pipeline has no text encoder"),
+            |e| e.into(),
+        ))
+    }
+
+    // LoRAs are merged into float weights, and int8 weights would need their
+    // quantization redone, so a quantized unet is swapped for its fp16 version.
+    pub async fn set_loras(
+        &mut self,
+        loras: &[(PathBuf, f32)],
+        mm: &ModelManager,
+        progress: impl Fn(String),
+    ) -> Result<()> {
+        if let (false, Some(name)) = (loras.is_empty(), self.diffuse_fp16) {
+            let mut diffuse = artspace_core::model::load_diffusion(
+                "ldm/ldm",
+                mm.download(name, &progress).await?,
+                &self.settings.session_options(true),
+            )?;
+            if let (Some(old), Some(new)) = (self.diffuse.controls_mut(), diffuse.controls_mut()) {
+                new.append(old);
+            }
+            self.diffuse = diffuse;
+            self.diffuse_fp16 = None;
+        }
+        Ok(self.diffuse.set_loras(loras)?)
+    }
+
+    pub fn set_super_resolution(&mut self, sr: Box<dyn artspace_core::model::SuperResolution>) {
+        self.sr = Some(sr);
+    }
+
+    pub fn set_face_restoration(
+        &mut self,
+        detector: Box<dyn FaceDetector>,
+        restoration: Box<dyn FaceRestoration>,
+    ) {
+        self.face = Some((detector, restoration));
+    }
+
+    pub fn set_safety_checker(&mut self, checker: Box<dyn SafetyChecker>, action: SafetyAction) {
+        self.safety = Some((checker, action));
+    }
+
+    // Whether each image of the last step was flagged by the safety checker.
+    pub fn flagged(&self) -> &[bool] {
+        &self.flagged
+    }
+
+    fn check_safety(
+        &mut self,
+        image: &mut ndarray::ArrayD<f32>,
+        progress: &impl Fn(String),
+    ) -> Result<()> {
+        self.flagged.clear();
+        if let Some((checker, action)) = &mut self.safety {
+            progress("Checking image safety...".to_string());
+            self.flagged = checker.check(image)?;
+            action.apply(image, &self.flagged);
+        }
+        Ok(())
+    }
+
+    pub fn add_control(
+        &mut self,
+        model: Box<dyn ControlNet>,
+        hint: ndarray::ArrayD<f32>,
+        strength: f32,
+        range: (f32, f32),
+    ) -> Result<()> {
+        let controls = self
+            .diffuse
+            .controls_mut()
+            .ok_or_else(|| anyhow::anyhow!("diffusion model does not support control"))?;
+        controls.push(Control {
+            model,
+            hint,
+            strength,
+            start: range.0,
+            end: range.1,
+        });
+        Ok(())
+    }
+
+    pub fn set_depth(
+        &mut self,
+        estimator: &mut dyn DepthEstimator,
+        image: &ndarray::ArrayD<f32>,
+    ) -> Result<()> {
+        self.depth = Some(normalize_depth(&estimator.estimate(image)?));
+        Ok(())
+    }
+
     pub async fn step_text(&mut self, s: &str) -> Result<()> {
         let f = futures::future::join_all(self.text_encoders.iter_mut().map(|e| {
             let s = s.to_owned();
@@ -128,6 +385,27 @@ impl Pipeline {
         Ok(())
     }
 
+    // Replaces the clip condition with an image embedding to generate variations of `image`.
+    pub async fn step_image(
+        &mut self,
+        encoder: &mut dyn ImageEncoder,
+        image: &ndarray::ArrayD<f32>,
+        text: &str,
+    ) -> Result<()> {
+        let idx = self
+            .text_encoders
+            .iter()
+            .position(|e| e.key == "clip")
+            .ok_or_else(|| anyhow::anyhow!("pipeline does not take a clip condition"))?;
+        self.step_text(text).await?;
+
+        let emb = encoder.encode(image)?;
+        let e = &mut self.text_embedding.as_mut().unwrap()[idx];
+        let uncond = e.slice_axis(Axis(0), Slice::from(..1usize)).to_owned();
+        *e = ndarray::concatenate(Axis(0), &[uncond.view(), emb.view()])?;
+        Ok(())
+    }
+
     pub async fn step_diffuse(
         &mut self,
         w: f32,
@@ -135,10 +413,6 @@ impl Pipeline {
         seed: Option<(ndarray::ArrayD<f32>, f32)>,
         progress: impl Fn(String),
     ) -> Result<ndarray::ArrayD<f32>> {
-        self.text_encoders
-            .iter_mut()
-            .for_each(|e| e.model.lock().unwrap().unload_model());
-
         let min = w.min(h);
 
         let (noise, sched) = if let Some((seed, strength)) = seed {
@@ -167,6 +441,49 @@ impl Pipeline {
             )
         };
 
+        let (mut cond, mut uncond) = self.text_conditions();
+
+        if let Some(depth) = &self.depth {
+            let depth = imgproc::resize(depth, noise.shape()[2], noise.shape()[3]);
+            cond.insert("depth".to_string(), depth.clone());
+            uncond.insert("depth".to_string(), depth);
+        }
+
+        let window = self.diffuse_output_size.0 / self.diffuse.image_scale();
+        let mut panorama;
+        let mut seamless;
+        let mut model: &mut dyn Diffusion = self.diffuse.as_mut();
+        if self.panorama {
+            panorama = MultiDiffusion::new(model, window, window / 2);
+            model = &mut panorama;
+        }
+        if self.tileable {
+            seamless = SeamlessDiffusion::new(model);
+            model = &mut seamless;
+        }
+
+        let d = {
+            let mut d = LmsSampler::new(model, &sched, cond, uncond, noise);
+            for (i, _) in sched.iter().enumerate() {
+                progress(format!("Diffusion step {}/{}", i + 1, sched.len()));
+                d.next(i);
+            }
+            d.seed
+        };
+
+        progress("Decoding image...".to_string());
+        let mut r = self.autoencoder.decode(&d)?;
+        progress("Decoding image done".to_string());
+        self.check_safety(&mut r, &progress)?;
+        Ok(r)
+    }
+
+    fn text_conditions(
+        &self,
+    ) -> (
+        HashMap<String, ndarray::ArrayD<f32>>,
+        HashMap<String, ndarray::ArrayD<f32>>,
+    ) {
         let mut cond = HashMap::<String, ndarray::ArrayD<f32>>::new();
         let mut uncond = HashMap::<String, ndarray::ArrayD<f32>>::new();
 
@@ -184,32 +501,75 @@ impl Pipeline {
                     .to_owned(),
             );
         }
-
-        let d = {
-            let mut d = LmsSampler::new(self.diffuse.as_mut(), &sched, cond, uncond, noise);
-            for (i, _) in sched.iter().enumerate() {
-                progress(format!("Diffusion step {}/{}", i + 1, sched.len()));
-                d.next(i);
-            }
-            d.seed
-        };
-
-        progress("Decoding image...".to_string());
-        let r = self.autoencoder.decode(&d)?;
-        progress("Decoding image done".to_string());
-        Ok(r)
+        (cond, uncond)
     }
 
     pub async fn step_post_process(
         &mut self,
         image: &ndarray::ArrayD<f32>,
+        progress: impl Fn(String),
     ) -> Result<ndarray::ArrayD<f32>> {
-        self.diffuse.unload_model();
-        Ok(if let Some(sr) = &mut self.sr {
-            sr.execute(image)?
+        if self.text_embedding.is_some() {
+            let (cond, uncond) = self.text_conditions();
+            if let Some(sr) = &mut self.sr {
+                sr.set_text_condition(&cond, &uncond);
+            }
+        }
+        let mut r = if let Some(sr) = &mut self.sr {
+            sr.execute_tiled(image, &self.sr_tile, &mut |i, n| {
+                progress(format!("Upscaling tile {}/{}", (i + 1).min(n), n))
+            })?
         } else {
             image.to_owned()
-        })
+        };
+        if let Some((detector, restoration)) = &mut self.face {
+            r = face::restore_faces(&r, detector.as_mut(), restoration.as_mut(), |i, n| {
+                progress(format!("Restoring face {}/{}", (i + 1).min(n), n))
+            })?;
+        }
+        self.check_safety(&mut r, &progress)?;
+        Ok(r)
+    }
+
+    // Scores each candidate against `text` and keeps the best `keep`, best first.
+    pub fn select_best(
+        scorer: &mut ClipScorer,
+        images: Vec<ndarray::ArrayD<f32>>,
+        text: &str,
+        keep: usize,
+    ) -> Result<Vec<(ndarray::ArrayD<f32>, Score)>> {
+        let mut scores = Vec::with_capacity(images.len());
+        for img in &images {
+            scores.extend(scorer.score(img, text)?);
+        }
+        let mut images = images.into_iter().map(Some).collect::<Vec<_>>();
+        Ok(scorer
+            .top_k(&scores, keep)
+            .into_iter()
+            .filter_map(|i| Some((images[i].take()?, scores[i])))
+            .collect())
+    }
+
+    // Suggests a prompt describing the image at `p`.
+    pub fn interrogate(
+        captioner: &mut dyn Captioner,
+        p: impl AsRef<Path>,
+        search: Search,
+    ) -> Result<String> {
+        let img = Self::open_image(p)?;
+        let caption = captioner.caption(&img, search)?;
+        Ok(caption.into_iter().next().unwrap_or_default())
+    }
+
+    pub fn open_image(p: impl AsRef<Path>) -> Result<ndarray::ArrayD<f32>> {
+        let img = image::io::Reader::open(p)?.decode()?.into_rgb8();
+        Ok(img
+            .into_ndarray3()
+            .insert_axis(ndarray::Axis(0))
+            .mapv(|x| f32::from(x) / 255.)
+            .as_standard_layout()
+            .to_owned()
+            .into_dyn())
     }
 
     pub fn open_seed(&self, p: impl AsRef<Path>) -> Result<ndarray::ArrayD<f32>> {
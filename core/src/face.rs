@@ -0,0 +1,184 @@
+use ndarray::{Axis, Ix3};
+
+use crate::{
+    model::{FaceDetector, FaceRestoration},
+    result::Result,
+};
+
+// Landmarks of the FFHQ aligned faces at 512x512.
+const FFHQ_TEMPLATE: [[f32; 2]; 5] = [
+    [192.98138, 239.94708],
+    [318.90277, 240.1936],
+    [256.63416, 314.01935],
+    [201.26117, 371.41043],
+    [313.08905, 371.15118],
+];
+
+// Width of the blend ramp at the border of a pasted face, relative to its size.
+const FEATHER: f32 = 0.08;
+
+// Maps (x, y) to (m[0] x + m[1] y + m[2], m[3] x + m[4] y + m[5]).
+pub type Affine = [f32; 6];
+
+// Least squares similarity transform (rotation, uniform scale and translation)
+// mapping `src` onto `dst`.
+pub fn similarity_transform(src: &[[f32; 2]], dst: &[[f32; 2]]) -> Affine {
+    let mean = |p: &[[f32; 2]]| {
+        let (x, y) = p.iter().fold((0., 0.), |(x, y), q| (x + q[0], y + q[1]));
+        [x / p.len() as f32, y / p.len() as f32]
+    };
+    let (ms, md) = (mean(src), mean(dst));
+
+    let (mut a, mut b, mut norm) = (0., 0., 0.);
+    for (s, d) in src.iter().zip(dst) {
+        let (sx, sy) = (s[0] - ms[0], s[1] - ms[1]);
+        let (dx, dy) = (d[0] - md[0], d[1] - md[1]);
+        a += sx * dx + sy * dy;
+        b += sx * dy - sy * dx;
+        norm += sx * sx + sy * sy;
+    }
+    let (a, b) = if norm > 0. {
+        (a / norm, b / norm)
+    } else {
+        (1., 0.)
+    };
+    [
+        a,
+        -b,
+        md[0] - a * ms[0] + b * ms[1],
+        b,
+        a,
+        md[1] - b * ms[0] - a * ms[1],
+    ]
+}
+
+pub fn invert_affine(m: &Affine) -> Affine {
+    let det = m[0] * m[4] - m[1] * m[3];
+    let (a, b, c, d) = (m[4] / det, -m[1] / det, -m[3] / det, m[0] / det);
+    [a, b, -(a * m[2] + b * m[5]), c, d, -(c * m[2] + d * m[5])]
+}
+
+fn transform(m: &Affine, x: f32, y: f32) -> (f32, f32) {
+    (m[0] * x + m[1] * y + m[2], m[3] * x + m[4] * y + m[5])
+}
+
+// Bilinear sample of a CHW image, clamping at the edges.
+fn sample(x: &ndarray::ArrayView3<f32>, c: usize, u: f32, v: f32) -> f32 {
+    let (h, w) = (x.shape()[1], x.shape()[2]);
+    let u = u.clamp(0., (w - 1) as f32);
+    let v = v.clamp(0., (h - 1) as f32);
+    let (x0, y0) = (u.floor() as usize, v.floor() as usize);
+    let (x1, y1) = ((x0 + 1).min(w - 1), (y0 + 1).min(h - 1));
+    let (fx, fy) = (u - x0 as f32, v - y0 as f32);
+    let top = x[[c, y0, x0]] * (1. - fx) + x[[c, y0, x1]] * fx;
+    let bottom = x[[c, y1, x0]] * (1. - fx) + x[[c, y1, x1]] * fx;
+    top * (1. - fy) + bottom * fy
+}
+
+// Resamples a CHW image into a `h` x `w` output, `inv` maps output to input pixels.
+pub fn warp_affine(
+    x: &ndarray::ArrayView3<f32>,
+    inv: &Affine,
+    h: usize,
+    w: usize,
+) -> ndarray::Array3<f32> {
+    ndarray::Array3::from_shape_fn((x.shape()[0], h, w), |(c, y, xx)| {
+        let (u, v) = transform(inv, xx as f32, y as f32);
+        sample(x, c, u, v)
+    })
+}
+
+// Blends an aligned face back into a CHW image, `m` maps image to face pixels.
+pub fn paste_face(x: &mut ndarray::Array3<f32>, face: &ndarray::ArrayView3<f32>, m: &Affine) {
+    let (h, w) = (x.shape()[1], x.shape()[2]);
+    let size = face.shape()[1].min(face.shape()[2]) as f32;
+    let inv = invert_affine(m);
+
+    let corners =
+        [(0., 0.), (size, 0.), (0., size), (size, size)].map(|(u, v)| transform(&inv, u, v));
+    let (x0, x1, y0, y1) = corners.iter().fold(
+        (f32::MAX, f32::MIN, f32::MAX, f32::MIN),
+        |(x0, x1, y0, y1), (x, y)| (x0.min(*x), x1.max(*x), y0.min(*y), y1.max(*y)),
+    );
+    let xs = (x0.floor().max(0.) as usize)..(x1.ceil().max(0.) as usize).min(w);
+    let ys = (y0.floor().max(0.) as usize)..(y1.ceil().max(0.) as usize).min(h);
+
+    let feather = FEATHER * size;
+    for y in ys {
+        for xx in xs.clone() {
+            let (u, v) = transform(m, xx as f32, y as f32);
+            let d = u.min(v).min(size - 1. - u).min(size - 1. - v);
+            if d < 0. {
+                continue;
+            }
+            let alpha = (d / feather).min(1.);
+            for c in 0..x.shape()[0] {
+                let p = &mut x[[c, y, xx]];
+                *p = *p * (1. - alpha) + sample(face, c, u, v) * alpha;
+            }
+        }
+    }
+}
+
+// Detects, aligns, restores and pastes back every face of a NCHW image batch.
+pub fn restore_faces<P>(
+    x: &ndarray::ArrayD<f32>,
+    detector: &mut dyn FaceDetector,
+    restoration: &mut dyn FaceRestoration,
+    mut progress: P,
+) -> Result<ndarray::ArrayD<f32>>
+where
+    P: FnMut(usize, usize),
+{
+    let size = restoration.face_size();
+    let scale = size as f32 / 512.;
+    let template = FFHQ_TEMPLATE.map(|[x, y]| [x * scale, y * scale]);
+
+    let mut out = x.to_owned();
+    for mut img in out.axis_iter_mut(Axis(0)) {
+        let faces = detector.detect(&img.to_owned().insert_axis(Axis(0)))?;
+        let mut restored = img.to_owned().into_dimensionality::<Ix3>().unwrap();
+        for (i, face) in faces.iter().enumerate() {
+            progress(i, faces.len());
+            let m = similarity_transform(&face.landmarks, &template);
+            let crop = warp_affine(&restored.view(), &invert_affine(&m), size, size);
+            let r = restoration.restore(&crop.insert_axis(Axis(0)).into_dyn())?;
+            let r = r
+                .index_axis(Axis(0), 0)
+                .into_dimensionality::<Ix3>()
+                .unwrap();
+            paste_face(&mut restored, &r, &m);
+        }
+        progress(faces.len(), faces.len());
+        img.assign(&restored.into_dyn());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_similarity_transform() {
+        let (s, t) = (0.5_f32, 0.3_f32);
+        let m: Affine = [
+            s * t.cos(),
+            -s * t.sin(),
+            10.,
+            s * t.sin(),
+            s * t.cos(),
+            -4.,
+        ];
+        let dst = FFHQ_TEMPLATE.map(|[x, y]| {
+            let (u, v) = transform(&m, x, y);
+            [u, v]
+        });
+        let r = similarity_transform(&FFHQ_TEMPLATE, &dst);
+        assert!(m.iter().zip(r).all(|(a, b)| (a - b).abs() < 1e-3));
+
+        let i = invert_affine(&r);
+        let (x, y) = transform(&i, dst[2][0], dst[2][1]);
+        assert!((x - FFHQ_TEMPLATE[2][0]).abs() < 1e-2 && (y - FFHQ_TEMPLATE[2][1]).abs() < 1e-2);
+    }
+}
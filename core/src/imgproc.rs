@@ -0,0 +1,102 @@
+use ndarray::{Axis, Ix4, Slice};
+
+// Bilinear resize of a NCHW tensor, sampling at pixel centers.
+pub fn resize(x: &ndarray::ArrayD<f32>, h: usize, w: usize) -> ndarray::ArrayD<f32> {
+    let x = x.view().into_dimensionality::<Ix4>().unwrap();
+    let (b, c, ih, iw) = x.dim();
+    if ih == h && iw == w {
+        return x.to_owned().into_dyn();
+    }
+
+    let sample = |o: usize, scale: f32, n: usize| {
+        let p = ((o as f32 + 0.5) * scale - 0.5).max(0.);
+        let i0 = (p.floor() as usize).min(n - 1);
+        let i1 = (i0 + 1).min(n - 1);
+        (i0, i1, p - i0 as f32)
+    };
+    let ys: Vec<_> = (0..h)
+        .map(|o| sample(o, ih as f32 / h as f32, ih))
+        .collect();
+    let xs: Vec<_> = (0..w)
+        .map(|o| sample(o, iw as f32 / w as f32, iw))
+        .collect();
+
+    ndarray::Array4::from_shape_fn((b, c, h, w), |(n, ch, y, xx)| {
+        let (y0, y1, fy) = ys[y];
+        let (x0, x1, fx) = xs[xx];
+        let top = x[[n, ch, y0, x0]] * (1. - fx) + x[[n, ch, y0, x1]] * fx;
+        let bottom = x[[n, ch, y1, x0]] * (1. - fx) + x[[n, ch, y1, x1]] * fx;
+        top * (1. - fy) + bottom * fy
+    })
+    .into_dyn()
+}
+
+pub fn center_crop(x: &ndarray::ArrayD<f32>, h: usize, w: usize) -> ndarray::ArrayD<f32> {
+    let (ih, iw) = (x.shape()[2], x.shape()[3]);
+    let (h, w) = (h.min(ih), w.min(iw));
+    let (y, xx) = ((ih - h) / 2, (iw - w) / 2);
+    x.slice_axis(Axis(2), Slice::from(y..y + h))
+        .slice_axis(Axis(3), Slice::from(xx..xx + w))
+        .as_standard_layout()
+        .to_owned()
+}
+
+// Resizes the shorter side to `size` and crops the center square.
+pub fn resize_center_crop(x: &ndarray::ArrayD<f32>, size: usize) -> ndarray::ArrayD<f32> {
+    let (ih, iw) = (x.shape()[2], x.shape()[3]);
+    let scale = size as f32 / ih.min(iw) as f32;
+    let h = ((ih as f32 * scale).round() as usize).max(size);
+    let w = ((iw as f32 * scale).round() as usize).max(size);
+    center_crop(&resize(x, h, w), size, size)
+}
+
+pub fn normalize(x: &mut ndarray::ArrayD<f32>, mean: &[f32], std: &[f32]) {
+    for (c, mut v) in x.axis_iter_mut(Axis(1)).enumerate() {
+        let (mean, std) = (mean[c], std[c]);
+        v.mapv_inplace(|v| (v - mean) / std);
+    }
+}
+
+// Separable box blur over the spatial axes of a NCHW tensor, clamping at the edges.
+pub fn box_blur(x: &ndarray::ArrayD<f32>, radius: usize) -> ndarray::ArrayD<f32> {
+    let mut y = x.as_standard_layout().to_owned();
+    for axis in [2, 3] {
+        let src = y.clone();
+        let mut sum = vec![];
+        for (mut dst, src) in y
+            .lanes_mut(Axis(axis))
+            .into_iter()
+            .zip(src.lanes(Axis(axis)))
+        {
+            let n = src.len();
+            sum.clear();
+            sum.push(0.);
+            for v in src.iter() {
+                sum.push(sum.last().unwrap() + v);
+            }
+            for i in 0..n {
+                let lo = i.saturating_sub(radius);
+                let hi = (i + radius + 1).min(n);
+                dst[i] = (sum[hi] - sum[lo]) / (hi - lo) as f32;
+            }
+        }
+    }
+    y
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resize() {
+        let x =
+            ndarray::ArrayD::from_shape_vec([1, 1, 2, 2].as_slice(), vec![0., 1., 2., 3.]).unwrap();
+        assert_eq!(resize(&x, 2, 2), x);
+        let y = resize(&x, 4, 4);
+        assert_eq!(y.shape(), &[1, 1, 4, 4]);
+        assert_eq!(y[[0, 0, 0, 0]], 0.);
+        assert_eq!(y[[0, 0, 3, 3]], 3.);
+        assert_eq!(resize(&y, 1, 1)[[0, 0, 0, 0]], 1.5);
+    }
+}
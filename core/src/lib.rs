@@ -3,7 +3,12 @@ extern crate lazy_static;
 #[macro_use]
 extern crate scopeguard;
 
+pub mod face;
+pub mod imgproc;
+pub mod merge;
 pub mod model;
 pub mod ort;
 mod result;
+pub mod safetensors;
 pub mod sampler;
+pub mod tile;
@@ -0,0 +1,356 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Read, Seek},
+    path::Path,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    ort::{initializer_from_f32, initializer_to_f32, DataType},
+    result::{Error, Result},
+};
+
+#[derive(Clone, Copy, Debug)]
+pub enum MergeMode {
+    // (1 - alpha) * a + alpha * b
+    WeightedSum { alpha: f32 },
+    // a + alpha * (b - c), e.g. to move a fine-tune's changes onto another model
+    AddDifference { alpha: f32 },
+}
+
+impl MergeMode {
+    pub fn num_inputs(&self) -> usize {
+        match self {
+            MergeMode::WeightedSum { .. } => 2,
+            MergeMode::AddDifference { .. } => 3,
+        }
+    }
+
+    fn combine(&self, values: &mut [Vec<f32>]) -> Vec<f32> {
+        let (first, rest) = values.split_first_mut().unwrap();
+        let mut out = std::mem::take(first);
+        match *self {
+            MergeMode::WeightedSum { alpha } => {
+                out.iter_mut()
+                    .zip(&rest[0])
+                    .for_each(|(a, b)| *a = (1. - alpha) * *a + alpha * b);
+            }
+            MergeMode::AddDifference { alpha } => {
+                out.iter_mut()
+                    .zip(rest[0].iter().zip(&rest[1]))
+                    .for_each(|(a, (b, c))| *a += alpha * (b - c));
+            }
+        }
+        out
+    }
+}
+
+// Merges tsar models of the same architecture blob by blob into a new tsar
+// archive. The files of the first model, graphs and blob maps included, are
+// copied as is, and the merged blobs keep its blob names, so the result loads
+// like any other model.
+pub fn merge_models<P>(
+    inputs: &[impl AsRef<Path>],
+    mode: MergeMode,
+    output: impl AsRef<Path>,
+    mut progress: P,
+) -> Result<()>
+where
+    P: FnMut(usize, usize),
+{
+    if inputs.len() != mode.num_inputs() {
+        return Err(Error::InvalidInput(format!(
+            "{:?} takes {} models, got {}",
+            mode,
+            mode.num_inputs(),
+            inputs.len()
+        )));
+    }
+    let output = output.as_ref();
+    let mut archives = inputs
+        .iter()
+        .map(|p| Ok(tsar::Archive::new(std::fs::File::open(p)?)?))
+        .collect::<Result<Vec<_>>>()?;
+
+    let files = archives[0]
+        .file_names()
+        .map(|s| s.to_owned())
+        .collect::<Vec<_>>();
+    let pairs = pair_blobs(&mut archives, &files)?;
+
+    // everything is checked before the first blob is merged
+    for (init, names) in &pairs {
+        let blobs = archives
+            .iter_mut()
+            .zip(names)
+            .map(|(a, n)| Ok(a.blob_by_name(n)?))
+            .collect::<Result<Vec<_>>>()?;
+        let shape = blobs[0].shape().into_iter().collect::<Vec<_>>();
+        if blobs
+            .iter()
+            .any(|b| b.shape().into_iter().collect::<Vec<_>>() != shape)
+        {
+            return Err(Error::InvalidInput(format!(
+                "models differ in the shape of {}",
+                init
+            )));
+        }
+        let elem_type = DataType::from(blobs[0].data_type());
+        if blobs
+            .iter()
+            .any(|b| DataType::from(b.data_type()) != elem_type)
+        {
+            return Err(Error::InvalidInput(format!(
+                "models differ in the type of {}",
+                init
+            )));
+        }
+        if matches!(elem_type, DataType::Int8 | DataType::Uint8) {
+            return Err(Error::Unsupported(format!(
+                "merging quantized models, {} is {:?}",
+                init, elem_type
+            )));
+        }
+    }
+
+    // written next to the output first, so a failed merge leaves no archive behind
+    let tmp = output.with_extension("tsar.tmp");
+    let mut builder = tsar::Builder::new(std::fs::File::create(&tmp)?);
+    for f in files.iter() {
+        builder.add_file(f, &mut archives[0].file_by_name(f)?)?;
+    }
+
+    for (i, (init, names)) in pairs.iter().enumerate() {
+        progress(i, pairs.len());
+
+        let mut blobs = archives
+            .iter_mut()
+            .zip(names)
+            .map(|(a, n)| Ok(a.blob_by_name(n)?))
+            .collect::<Result<Vec<_>>>()?;
+        let elem_type = DataType::from(blobs[0].data_type());
+
+        let data = blobs
+            .iter_mut()
+            .map(|b| {
+                let mut buf = Vec::new();
+                b.read_to_end(&mut buf)?;
+                Ok(buf)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let merged = if data.iter().all(|d| *d == data[0]) {
+            data.into_iter().next().unwrap()
+        } else if matches!(
+            elem_type,
+            DataType::Float32 | DataType::Float16 | DataType::Bfloat16
+        ) {
+            let mut values = data
+                .iter()
+                .map(|d| initializer_to_f32(elem_type, d))
+                .collect::<Result<Vec<_>>>()?;
+            initializer_from_f32(elem_type, &mode.combine(&mut values))?
+        } else {
+            return Err(Error::Unsupported(format!(
+                "merging {} of type {:?}",
+                init, elem_type
+            )));
+        };
+
+        let shape = blobs[0].shape().into_iter().collect::<Vec<_>>();
+        builder.add_blob(
+            &names[0],
+            blobs[0].data_type(),
+            &shape,
+            blobs[0].target_file(),
+            &merged,
+        )?;
+    }
+    builder.finish()?;
+    std::fs::rename(&tmp, output)?;
+    progress(pairs.len(), pairs.len());
+    Ok(())
+}
+
+// Pairs the blobs of the models by the initializer they hold, as listed in
+// the .<graph>.onnx.json of every graph, since blob names may differ between
+// archives. Returns the initializer and the blob name in each archive.
+fn pair_blobs<R>(
+    archives: &mut [tsar::Archive<R>],
+    files: &[String],
+) -> Result<Vec<(String, Vec<String>)>>
+where
+    R: Read + Seek,
+{
+    #[derive(Deserialize)]
+    struct OnnxMeta {
+        blobs: HashMap<String, String>,
+    }
+
+    let mut pairs = Vec::new();
+    let mut seen = HashSet::new();
+    for graph in files.iter().filter(|f| f.ends_with(".onnx")) {
+        let meta_name = format!(".{}.json", graph);
+        if !files.contains(&meta_name) {
+            continue;
+        }
+        let metas = archives
+            .iter_mut()
+            .map(|a| {
+                let meta: OnnxMeta = serde_json::from_reader(a.file_by_name(&meta_name)?)?;
+                Ok(meta.blobs)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut inits = metas[0].keys().cloned().collect::<Vec<_>>();
+        inits.sort();
+        for init in inits {
+            let names = metas
+                .iter()
+                .enumerate()
+                .map(|(i, m)| {
+                    m.get(&init).cloned().ok_or_else(|| {
+                        Error::InvalidInput(format!(
+                            "model {} has no initializer {} in {}",
+                            i + 1,
+                            init,
+                            graph
+                        ))
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            // graphs may share a blob
+            if seen.insert(names[0].clone()) {
+                pairs.push((init, names));
+            }
+        }
+    }
+    Ok(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ort::Session;
+
+    fn varint(mut v: u64, out: &mut Vec<u8>) {
+        while v >= 0x80 {
+            out.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+
+    fn field(k: u64, v: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        varint((k << 3) | 2, &mut out);
+        varint(v.len() as u64, &mut out);
+        out.extend_from_slice(v);
+        out
+    }
+
+    fn int(k: u64, v: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        varint(k << 3, &mut out);
+        varint(v, &mut out);
+        out
+    }
+
+    // y = x + w, with w a float[2] blob of the archive
+    fn write_model(path: &Path, w: [f32; 2]) {
+        let value_info = |name: &[u8]| {
+            let dim = field(1, &int(1, 2));
+            let tensor = [int(1, 1), field(2, &field(1, &dim))].concat();
+            [field(1, name), field(2, &field(1, &tensor))].concat()
+        };
+        let location = [field(1, b"location"), field(2, b"w.bin")].concat();
+        let init = [
+            int(1, 2),
+            int(2, 1),
+            field(8, b"w"),
+            field(13, &location),
+            int(14, 1),
+        ]
+        .concat();
+        let node = [
+            field(1, b"x"),
+            field(1, b"w"),
+            field(2, b"y"),
+            field(4, b"Add"),
+        ]
+        .concat();
+        let graph = [
+            field(1, &node),
+            field(2, b"merge"),
+            field(5, &init),
+            field(11, &value_info(b"x")),
+            field(12, &value_info(b"y")),
+        ]
+        .concat();
+        let model = [int(1, 7), field(7, &graph), field(8, &int(2, 13))].concat();
+
+        let data = w.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+        let mut builder = tsar::Builder::new(std::fs::File::create(path).unwrap());
+        builder.add_file("model.onnx", &mut &model[..]).unwrap();
+        builder
+            .add_file(".model.onnx.json", &mut &br#"{"blobs":{"w":"w"}}"#[..])
+            .unwrap();
+        builder
+            .add_blob(
+                "w",
+                Some(tsar::DataType::Float32),
+                &[2],
+                Some(("w.bin", 0)),
+                &data,
+            )
+            .unwrap();
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn test_merge_models() {
+        let dir = std::env::temp_dir().join(format!("artspace-merge-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let inputs = [[1., 2.], [3., 6.]]
+            .into_iter()
+            .enumerate()
+            .map(|(i, w)| {
+                let path = dir.join(format!("{}.tsar", i));
+                write_model(&path, w);
+                path
+            })
+            .collect::<Vec<_>>();
+        let output = dir.join("merged.tsar");
+        merge_models(
+            &inputs,
+            MergeMode::WeightedSum { alpha: 0.5 },
+            &output,
+            |_, _| {},
+        )
+        .unwrap();
+
+        let session = Session::load(&output, "model.onnx").unwrap();
+        let x = ndarray::ArrayD::<f32>::zeros([2].as_slice());
+        let mut run = session.prepare();
+        run.set_input("x", &x).unwrap();
+        let out = run.exec(false).unwrap();
+        let y = out.get_output_idx::<f32, ndarray::IxDyn>(0).unwrap();
+        assert_eq!(y.as_slice().unwrap(), &[2., 4.]);
+        drop(out);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_combine() {
+        let mode = MergeMode::WeightedSum { alpha: 0.25 };
+        assert_eq!(
+            mode.combine(&mut [vec![0., 4.], vec![4., 0.]]),
+            vec![1., 3.]
+        );
+        let mode = MergeMode::AddDifference { alpha: 0.5 };
+        assert_eq!(
+            mode.combine(&mut [vec![1., 1.], vec![3., 2.], vec![1., 2.]]),
+            vec![2., 1.]
+        );
+    }
+}
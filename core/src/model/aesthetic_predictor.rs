@@ -0,0 +1,30 @@
+use std::path::Path;
+
+use super::{clip, kind::resolve_kind, Model};
+use crate::{
+    ort::SessionOptions,
+    result::{Error, Result},
+};
+
+pub trait AestheticPredictor: Model {
+    // Predicts a quality score for each CLIP image embedding in the batch.
+    fn predict(&mut self, emb: &ndarray::ArrayD<f32>) -> Result<ndarray::Array1<f32>>;
+}
+
+pub fn load_aesthetic_predictor(
+    kind: impl AsRef<str>,
+    path: impl AsRef<Path>,
+    options: &SessionOptions,
+) -> Result<Box<dyn AestheticPredictor>> {
+    match resolve_kind(kind.as_ref(), path.as_ref())?.as_str() {
+        "clip/aesthetic" => Ok(Box::new(clip::AestheticHead::new(
+            path.as_ref(),
+            options.clone(),
+        )?)),
+
+        k => Err(Error::UnsupportedModel(
+            "aesthetic predictor".to_string(),
+            k.to_owned(),
+        )),
+    }
+}
@@ -1,19 +1,26 @@
 use std::path::Path;
 
-use super::{ldm::vq, Model};
-use crate::result::{Error, Result};
+use super::{kind::resolve_kind, ldm::vq, Model};
+use crate::{
+    ort::SessionOptions,
+    result::{Error, Result},
+    tile::Tiling,
+};
 
 pub trait AutoEncoder: Model {
     fn encode(&mut self, x: &ndarray::ArrayD<f32>) -> Result<ndarray::ArrayD<f32>>;
     fn decode(&mut self, x: &ndarray::ArrayD<f32>) -> Result<ndarray::ArrayD<f32>>;
+    fn set_tiling(&mut self, _tiling: Tiling) {}
+    fn set_seamless(&mut self, _seamless: bool) {}
 }
 
 pub fn load_auto_encoder(
     kind: impl AsRef<str>,
     path: impl AsRef<Path>,
+    options: &SessionOptions,
 ) -> Result<Box<dyn AutoEncoder>> {
-    match kind.as_ref() {
-        "ldm/vq" => Ok(Box::new(vq::Vq::new(path.as_ref())?)),
+    match resolve_kind(kind.as_ref(), path.as_ref())?.as_str() {
+        "ldm/vq" => Ok(Box::new(vq::Vq::new(path.as_ref(), options.clone())?)),
 
         k => Err(Error::UnsupportedModel("diffuse".to_string(), k.to_owned())),
     }
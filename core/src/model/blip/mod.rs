@@ -0,0 +1,259 @@
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use ndarray::Axis;
+use serde::Deserialize;
+use tokenizers::Tokenizer;
+
+use super::{Captioner, Model, Search};
+use crate::{
+    imgproc,
+    ort::{Session, SessionOptions, TensorInfo},
+    result::{Error, Result},
+};
+
+// BLIP captioning model, split into a vision transformer and a text decoder
+// that is run over the whole sequence at every step.
+pub struct Blip {
+    metadata: Metadata,
+    tokenizer: Tokenizer,
+    path: PathBuf,
+    visual_session: Option<Session>,
+    decoder_session: Option<Session>,
+    options: SessionOptions,
+    decoder_inputs: HashMap<String, TensorInfo>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct Metadata {
+    image_size: usize,
+    mean: [f32; 3],
+    std: [f32; 3],
+    bos_token_id: u32,
+    eos_token_id: u32,
+    prompt: String,
+    max_length: usize,
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Self {
+            image_size: 384,
+            mean: [0.48145466, 0.4578275, 0.40821073],
+            std: [0.26862954, 0.2613026, 0.2757771],
+            bos_token_id: 30522,
+            eos_token_id: 102,
+            prompt: "a picture of ".to_string(),
+            max_length: 30,
+        }
+    }
+}
+
+struct Beam {
+    ids: Vec<i64>,
+    logprob: f32,
+    done: bool,
+}
+
+impl Beam {
+    fn score(&self) -> f32 {
+        self.logprob / self.ids.len() as f32
+    }
+}
+
+impl Captioner for Blip {
+    fn caption(&mut self, x: &ndarray::ArrayD<f32>, search: Search) -> Result<Vec<String>> {
+        if x.is_empty() {
+            return Ok(vec![]);
+        }
+        if x.ndim() != 4 || x.shape()[1] != 3 {
+            return Err(Error::InvalidInput(format!(
+                "expected RGB image, got shape {:?}",
+                x.shape()
+            )));
+        }
+
+        let visual = if let Some(session) = &self.visual_session {
+            session
+        } else {
+            self.visual_session.insert(Session::load_with(
+                &self.path,
+                "visual.onnx",
+                &self.options,
+            )?)
+        };
+
+        let mut inp = imgproc::resize(x, self.metadata.image_size, self.metadata.image_size);
+        imgproc::normalize(&mut inp, &self.metadata.mean, &self.metadata.std);
+
+        let mut run = visual.prepare();
+        run.set_input("input", &inp)?;
+        let out = run.exec(true)?;
+        let embeds = out.get_output_idx::<f32, ndarray::IxDyn>(0)?.to_owned();
+
+        if self.decoder_session.is_none() {
+            let s = Session::load_with(&self.path, "text_decoder.onnx", &self.options)?;
+            self.decoder_inputs = s.inputs()?;
+            self.decoder_session = Some(s);
+        }
+
+        let prompt = self
+            .tokenizer
+            .encode(self.metadata.prompt.as_str(), false)
+            .map_err(|e| Error::Tokenizer(e))?;
+        let mut prefix = vec![self.metadata.bos_token_id as i64];
+        prefix.extend(prompt.get_ids().iter().map(|&v| v as i64));
+
+        let width = match search {
+            Search::Greedy => 1,
+            Search::Beam { width } => width.max(1),
+        };
+        embeds
+            .axis_iter(Axis(0))
+            .map(|e| {
+                let ids = self.beam_search(&e.insert_axis(Axis(0)), &prefix, width)?;
+                let ids = ids[prefix.len()..].iter().map(|&v| v as u32).collect();
+                let text = self
+                    .tokenizer
+                    .decode(ids, true)
+                    .map_err(|e| Error::Tokenizer(e))?;
+                Ok(text.trim().to_string())
+            })
+            .collect()
+    }
+}
+
+impl Model for Blip {
+    fn unload_model(&mut self) {
+        self.visual_session = None;
+        self.decoder_session = None;
+    }
+}
+
+impl Blip {
+    pub fn new(path: impl Into<PathBuf>, options: SessionOptions) -> Result<Self> {
+        let path = path.into();
+        let metadata: Metadata = serde_json::from_str(&read_file(&path, "metadata.json")?)?;
+        let tokenizer = Tokenizer::from_str(&read_file(&path, "tokenizer.json")?)
+            .map_err(|e| Error::Tokenizer(e))?;
+
+        Ok(Self {
+            metadata,
+            tokenizer,
+            path,
+            visual_session: None,
+            decoder_session: None,
+            options,
+            decoder_inputs: HashMap::new(),
+        })
+    }
+
+    // Log probabilities of the next token for each sequence of the batch.
+    fn next_token(
+        &self,
+        ids: &ndarray::Array2<i64>,
+        embeds: &ndarray::ArrayViewD<f32>,
+    ) -> Result<ndarray::Array2<f32>> {
+        let session = self.decoder_session.as_ref().ok_or(Error::Unknown)?;
+        let batch = ids.shape()[0];
+        let mut shape = embeds.shape().to_vec();
+        shape[0] = batch;
+        let embeds = embeds
+            .broadcast(shape.as_slice())
+            .ok_or(Error::Unknown)?
+            .to_owned();
+        let mask = ndarray::Array2::<i64>::ones(ids.dim());
+
+        let mut run = session.prepare();
+        run.set_input("input_ids", ids)?;
+        run.set_input("encoder_hidden_states", &embeds)?;
+        if self.decoder_inputs.contains_key("attention_mask") {
+            run.set_input("attention_mask", &mask)?;
+        }
+        let out = run.exec(false)?;
+        let logits = out.get_output_idx::<f32, ndarray::Ix3>(0)?;
+        let last = logits.index_axis(Axis(1), logits.shape()[1] - 1);
+
+        let mut out = last.to_owned();
+        for mut row in out.axis_iter_mut(Axis(0)) {
+            let max = row.fold(f32::MIN, |a, b| a.max(*b));
+            let lse = row.fold(0., |a, b| a + (b - max).exp()).ln() + max;
+            row.mapv_inplace(|v| v - lse);
+        }
+        Ok(out)
+    }
+
+    // Beam search over a single image, a width of 1 is greedy decoding.
+    fn beam_search(
+        &self,
+        embeds: &ndarray::ArrayViewD<f32>,
+        prefix: &[i64],
+        width: usize,
+    ) -> Result<Vec<i64>> {
+        let eos = self.metadata.eos_token_id as i64;
+        let mut beams = vec![Beam {
+            ids: prefix.to_vec(),
+            logprob: 0.,
+            done: false,
+        }];
+
+        for _ in 0..self.metadata.max_length {
+            let (done, active): (Vec<_>, Vec<_>) = beams.into_iter().partition(|b| b.done);
+            if active.is_empty() {
+                beams = done;
+                break;
+            }
+
+            let ids = ndarray::Array2::from_shape_vec(
+                (active.len(), active[0].ids.len()),
+                active.iter().flat_map(|b| b.ids.iter().copied()).collect(),
+            )
+            .unwrap();
+            let logprobs = self.next_token(&ids, embeds)?;
+
+            let mut candidates = done;
+            for (b, lp) in active.iter().zip(logprobs.axis_iter(Axis(0))) {
+                let mut top: Vec<_> = lp.iter().copied().enumerate().collect();
+                top.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+                for &(token, p) in top.iter().take(width) {
+                    let mut ids = b.ids.clone();
+                    ids.push(token as i64);
+                    candidates.push(Beam {
+                        ids,
+                        logprob: b.logprob + p,
+                        done: token as i64 == eos,
+                    });
+                }
+            }
+            candidates.sort_unstable_by(|a, b| b.score().total_cmp(&a.score()));
+            candidates.truncate(width);
+            beams = candidates;
+        }
+
+        let best = beams
+            .into_iter()
+            .max_by(|a, b| a.score().total_cmp(&b.score()))
+            .ok_or(Error::Unknown)?;
+        let mut ids = best.ids;
+        if let Some(end) = ids.iter().position(|&t| t == eos) {
+            ids.truncate(end);
+        }
+        Ok(ids)
+    }
+}
+
+fn read_file(path: &Path, name: &str) -> Result<String> {
+    if path.is_dir() {
+        Ok(std::fs::read_to_string(path.join(name))?)
+    } else {
+        let mut ar = tsar::Archive::new(std::fs::File::open(path)?)?;
+        let mut buf = String::new();
+        ar.file_by_name(name)?.read_to_string(&mut buf)?;
+        Ok(buf)
+    }
+}
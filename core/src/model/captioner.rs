@@ -0,0 +1,33 @@
+use std::path::Path;
+
+use super::{blip::Blip, kind::resolve_kind, Model};
+use crate::{
+    ort::SessionOptions,
+    result::{Error, Result},
+};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Search {
+    Greedy,
+    Beam { width: usize },
+}
+
+pub trait Captioner: Model {
+    // Describes each image of a NCHW batch in [0, 1].
+    fn caption(&mut self, x: &ndarray::ArrayD<f32>, search: Search) -> Result<Vec<String>>;
+}
+
+pub fn load_captioner(
+    kind: impl AsRef<str>,
+    path: impl AsRef<Path>,
+    options: &SessionOptions,
+) -> Result<Box<dyn Captioner>> {
+    match resolve_kind(kind.as_ref(), path.as_ref())?.as_str() {
+        "blip" => Ok(Box::new(Blip::new(path.as_ref(), options.clone())?)),
+
+        k => Err(Error::UnsupportedModel(
+            "captioner".to_string(),
+            k.to_owned(),
+        )),
+    }
+}
@@ -0,0 +1,63 @@
+use std::path::PathBuf;
+
+use ndarray::Axis;
+
+use crate::{
+    model::{aesthetic_predictor::AestheticPredictor, Model},
+    ort::{Session, SessionOptions},
+    result::Result,
+};
+
+pub struct AestheticHead {
+    path: PathBuf,
+    session: Option<Session>,
+    options: SessionOptions,
+}
+
+impl AestheticPredictor for AestheticHead {
+    fn predict(&mut self, emb: &ndarray::ArrayD<f32>) -> Result<ndarray::Array1<f32>> {
+        if emb.is_empty() {
+            return Ok(ndarray::Array1::zeros(0));
+        }
+
+        let session = if let Some(session) = &self.session {
+            session
+        } else {
+            self.session.insert(Session::load_with(
+                &self.path,
+                "aesthetic.onnx",
+                &self.options,
+            )?)
+        };
+
+        // the head is trained on L2 normalized embeddings
+        let norm = emb
+            .mapv(|v| v * v)
+            .sum_axis(Axis(emb.ndim() - 1))
+            .mapv(f32::sqrt)
+            .insert_axis(Axis(emb.ndim() - 1));
+        let emb = emb / norm;
+
+        let mut run = session.prepare();
+        run.set_input("input", &emb)?;
+        let out = run.exec(true)?;
+        let out = out.get_output_idx::<f32, ndarray::IxDyn>(0)?;
+        Ok(out.iter().copied().collect())
+    }
+}
+
+impl Model for AestheticHead {
+    fn unload_model(&mut self) {
+        self.session = None;
+    }
+}
+
+impl AestheticHead {
+    pub fn new(path: impl Into<PathBuf>, options: SessionOptions) -> Result<Self> {
+        Ok(Self {
+            path: path.into(),
+            session: None,
+            options,
+        })
+    }
+}
@@ -0,0 +1,41 @@
+use crate::{
+    ort::{initializer_from_f32, initializer_to_f32, DataType, InitializerTransform},
+    result::Result,
+};
+
+// Writes textual inversion vectors into the rows of their placeholder tokens,
+// growing the token embedding table found by its [vocab, dim] shape.
+#[derive(Clone)]
+pub struct TokenEmbeddings {
+    pub vocab: usize,
+    pub dim: usize,
+    pub rows: Vec<(usize, Vec<f32>)>,
+}
+
+impl InitializerTransform for TokenEmbeddings {
+    fn applies(&self, _name: &str, shape: &[usize]) -> bool {
+        shape.len() == 2 && shape[0] == self.vocab && shape[1] == self.dim
+    }
+
+    fn apply(
+        &self,
+        _name: &str,
+        elem_type: DataType,
+        shape: &mut Vec<usize>,
+        data: &mut Vec<u8>,
+    ) -> Result<()> {
+        let mut w = initializer_to_f32(elem_type, data)?;
+        let rows = self
+            .rows
+            .iter()
+            .map(|(id, _)| id + 1)
+            .fold(shape[0], usize::max);
+        w.resize(rows * self.dim, 0.);
+        for (id, v) in &self.rows {
+            w[id * self.dim..(id + 1) * self.dim].copy_from_slice(v);
+        }
+        shape[0] = rows;
+        *data = initializer_from_f32(elem_type, &w)?;
+        Ok(())
+    }
+}
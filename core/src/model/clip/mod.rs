@@ -1,22 +1,45 @@
-use std::{io::Read, path::PathBuf, str::FromStr};
+mod aesthetic;
+mod embedding;
+mod safety;
+mod visual;
 
-use tokenizers::Tokenizer;
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use tokenizers::{AddedToken, Tokenizer};
 
 use crate::{
     model::{text_encoder::TextEncoder, Model},
-    ort::Session,
+    ort::{InitializerTransform, Session, SessionOptions},
     result::{Error, Result},
+    safetensors,
 };
 
+pub use aesthetic::AestheticHead;
+pub use safety::ClipSafetyChecker;
+pub use visual::ClipImageEncoder;
+
 pub struct ClipEncoder {
     tokenizer: Tokenizer,
     path: PathBuf,
     out_idx: usize,
     session: Option<Session>,
+    options: SessionOptions,
+    embeddings: Option<embedding::TokenEmbeddings>,
+    // placeholders of multi-vector embeddings and the tokens they stand for
+    expansions: Vec<(String, String)>,
 }
 
 impl TextEncoder for ClipEncoder {
     fn tokenize(&mut self, inp: &str) -> Result<tokenizers::Encoding> {
+        let mut inp = inp.to_owned();
+        for (token, expanded) in &self.expansions {
+            inp = inp.replace(token.as_str(), expanded);
+        }
         self.tokenizer
             .encode(inp, true)
             .map_err(|e| Error::Tokenizer(e))
@@ -31,8 +54,18 @@ impl TextEncoder for ClipEncoder {
         let session = if let Some(session) = &self.session {
             session
         } else {
-            self.session
-                .insert(Session::load(&self.path, "textual.onnx", true)?)
+            // the session keeps its own copy to load again with
+            let transforms: Vec<Arc<dyn InitializerTransform>> = self
+                .embeddings
+                .iter()
+                .map(|e| Arc::new(e.clone()) as Arc<dyn InitializerTransform>)
+                .collect();
+            self.session.insert(Session::load_transformed(
+                &self.path,
+                "textual.onnx",
+                &self.options,
+                &transforms,
+            )?)
         };
 
         let enc = ndarray::Array2::from_shape_vec(
@@ -50,6 +83,66 @@ impl TextEncoder for ClipEncoder {
         let out = out.get_output_idx::<f32, ndarray::IxDyn>(self.out_idx)?;
         Ok(out.into_dyn().to_owned())
     }
+
+    fn add_embeddings(&mut self, path: &Path) -> Result<Vec<String>> {
+        let vocab = self.tokenizer.get_vocab_size(false);
+        let mut added = Vec::new();
+        for (name, t) in safetensors::load(path)? {
+            // webui embeddings store their vectors under a fixed key
+            let token = if name == "emb_params" {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                format!("<{}>", stem)
+            } else {
+                name
+            };
+            let dim = t.shape().last().copied().unwrap_or(0);
+            if dim == 0 || t.is_empty() {
+                return Err(Error::InvalidInput(format!("empty embedding {}", token)));
+            }
+
+            let values: Vec<f32> = t.iter().copied().collect();
+            let names: Vec<String> = (0..values.len() / dim)
+                .map(|i| match i {
+                    0 => token.clone(),
+                    i => format!("{}_{}", token, i),
+                })
+                .collect();
+            self.tokenizer.add_special_tokens(
+                &names
+                    .iter()
+                    .map(|n| AddedToken::from(n.clone(), true))
+                    .collect::<Vec<_>>(),
+            );
+
+            let emb = self
+                .embeddings
+                .get_or_insert_with(|| embedding::TokenEmbeddings {
+                    vocab,
+                    dim,
+                    rows: Vec::new(),
+                });
+            if emb.dim != dim {
+                return Err(Error::InvalidInput(format!(
+                    "embedding {} has dimension {}, expected {}",
+                    token, dim, emb.dim
+                )));
+            }
+            for (n, row) in names.iter().zip(values.chunks(dim)) {
+                let id = self.tokenizer.token_to_id(n).ok_or(Error::Unknown)? as usize;
+                emb.rows.retain(|(r, _)| *r != id);
+                emb.rows.push((id, row.to_vec()));
+            }
+
+            self.expansions.retain(|(t, _)| *t != token);
+            if names.len() > 1 {
+                self.expansions.push((token.clone(), names.join(" ")));
+            }
+            added.push(token);
+        }
+
+        self.session = None;
+        Ok(added)
+    }
 }
 
 impl Model for ClipEncoder {
@@ -59,7 +152,7 @@ impl Model for ClipEncoder {
 }
 
 impl ClipEncoder {
-    pub fn new(path: impl Into<PathBuf>, out_idx: usize) -> Result<Self> {
+    pub fn new(path: impl Into<PathBuf>, out_idx: usize, options: SessionOptions) -> Result<Self> {
         let path = path.into();
         let tokenizer_json = if path.is_dir() {
             std::fs::read_to_string(path.join("tokenizer.json"))?
@@ -88,6 +181,9 @@ impl ClipEncoder {
             path,
             out_idx,
             session: None,
+            options,
+            embeddings: None,
+            expansions: Vec::new(),
         })
     }
 }
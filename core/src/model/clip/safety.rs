@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+
+use ndarray::{Axis, Slice};
+
+use crate::{
+    imgproc,
+    model::{safety_checker::SafetyChecker, Model},
+    ort::{Session, SessionOptions},
+    result::{Error, Result},
+    tile,
+};
+
+const IMAGE_SIZE: usize = 224;
+const MEAN: [f32; 3] = [0.48145466, 0.4578275, 0.40821073];
+const STD: [f32; 3] = [0.26862954, 0.2613026, 0.2757771];
+
+// Stable Diffusion safety checker. The exported graph takes CLIP preprocessed
+// images and returns, per image, the largest concept similarity minus its
+// threshold, so positive scores are flagged. Wide images, such as panoramas,
+// are checked as overlapping squares, and flagged if any square is.
+pub struct ClipSafetyChecker {
+    path: PathBuf,
+    session: Option<Session>,
+    options: SessionOptions,
+}
+
+impl SafetyChecker for ClipSafetyChecker {
+    fn check(&mut self, x: &ndarray::ArrayD<f32>) -> Result<Vec<bool>> {
+        if x.is_empty() {
+            return Ok(vec![]);
+        }
+        if x.ndim() != 4 || x.shape()[1] != 3 {
+            return Err(Error::InvalidInput(format!(
+                "expected RGB image, got shape {:?}",
+                x.shape()
+            )));
+        }
+
+        let session = if let Some(session) = &self.session {
+            session
+        } else {
+            self.session.insert(Session::load_with(
+                &self.path,
+                "safety_checker.onnx",
+                &self.options,
+            )?)
+        };
+
+        let (h, w) = (x.shape()[2], x.shape()[3]);
+        let side = h.min(w);
+        let rows = tile::tile_ranges(h, side, side / 2, 1);
+        let cols = tile::tile_ranges(w, side, side / 2, 1);
+        let crops = rows
+            .iter()
+            .flat_map(|r| cols.iter().map(move |c| (r.clone(), c.clone())))
+            .map(|(r, c)| {
+                let crop = x
+                    .slice_axis(Axis(2), Slice::from(r))
+                    .slice_axis(Axis(3), Slice::from(c))
+                    .to_owned();
+                imgproc::resize(&crop, IMAGE_SIZE, IMAGE_SIZE)
+            })
+            .collect::<Vec<_>>();
+        let per_image = crops.len();
+        // crop-major, so image i of crop j is at j * batch + i
+        let views: Vec<_> = crops.iter().map(|c| c.view()).collect();
+        let mut inp = ndarray::concatenate(Axis(0), &views)
+            .map_err(|e| Error::InvalidInput(format!("invalid safety input: {}", e)))?;
+        imgproc::normalize(&mut inp, &MEAN, &STD);
+
+        let mut run = session.prepare();
+        run.set_input("clip_input", &inp)?;
+        let out = run.exec(true)?;
+        let out = out.get_output_idx::<f32, ndarray::IxDyn>(0)?;
+        let flagged: Vec<bool> = out
+            .axis_iter(Axis(0))
+            .map(|s| s.iter().any(|v| *v > 0.))
+            .collect();
+        let batch = x.shape()[0];
+        Ok((0..batch)
+            .map(|i| (0..per_image).any(|j| flagged[j * batch + i]))
+            .collect())
+    }
+}
+
+impl Model for ClipSafetyChecker {
+    fn unload_model(&mut self) {
+        self.session = None;
+    }
+}
+
+impl ClipSafetyChecker {
+    pub fn new(path: impl Into<PathBuf>, options: SessionOptions) -> Result<Self> {
+        Ok(Self {
+            path: path.into(),
+            session: None,
+            options,
+        })
+    }
+}
@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+
+use crate::{
+    imgproc,
+    model::{image_encoder::ImageEncoder, Model},
+    ort::{Session, SessionOptions},
+    result::{Error, Result},
+};
+
+const IMAGE_SIZE: usize = 224;
+const MEAN: [f32; 3] = [0.48145466, 0.4578275, 0.40821073];
+const STD: [f32; 3] = [0.26862954, 0.2613026, 0.2757771];
+
+pub struct ClipImageEncoder {
+    path: PathBuf,
+    session: Option<Session>,
+    options: SessionOptions,
+}
+
+impl ImageEncoder for ClipImageEncoder {
+    fn encode(&mut self, x: &ndarray::ArrayD<f32>) -> Result<ndarray::ArrayD<f32>> {
+        if x.is_empty() {
+            return Ok(ndarray::ArrayD::zeros([].as_slice()));
+        }
+        if x.ndim() != 4 || x.shape()[1] != 3 {
+            return Err(Error::InvalidInput(format!(
+                "expected RGB image, got shape {:?}",
+                x.shape()
+            )));
+        }
+
+        let session = if let Some(session) = &self.session {
+            session
+        } else {
+            self.session.insert(Session::load_with(
+                &self.path,
+                "visual.onnx",
+                &self.options,
+            )?)
+        };
+
+        let mut inp = imgproc::resize_center_crop(x, IMAGE_SIZE);
+        imgproc::normalize(&mut inp, &MEAN, &STD);
+
+        let mut run = session.prepare();
+        run.set_input("input", &inp)?;
+        let out = run.exec(true)?;
+        let out = out.get_output_idx::<f32, ndarray::IxDyn>(0)?;
+        Ok(out.to_owned())
+    }
+}
+
+impl Model for ClipImageEncoder {
+    fn unload_model(&mut self) {
+        self.session = None;
+    }
+}
+
+impl ClipImageEncoder {
+    pub fn new(path: impl Into<PathBuf>, options: SessionOptions) -> Result<Self> {
+        Ok(Self {
+            path: path.into(),
+            session: None,
+            options,
+        })
+    }
+}
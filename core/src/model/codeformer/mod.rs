@@ -0,0 +1,81 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use super::{FaceRestoration, Model};
+use crate::{
+    ort::{DataType, Session, SessionOptions, TensorInfo},
+    result::{Error, Result},
+};
+
+pub struct CodeFormer {
+    path: PathBuf,
+    session: Option<Session>,
+    options: SessionOptions,
+    input_types: HashMap<String, TensorInfo>,
+    fidelity: f32,
+}
+
+impl FaceRestoration for CodeFormer {
+    fn restore(&mut self, x: &ndarray::ArrayD<f32>) -> Result<ndarray::ArrayD<f32>> {
+        if x.is_empty() {
+            return Ok(ndarray::ArrayD::zeros([].as_slice()));
+        }
+
+        let session = if let Some(session) = &self.session {
+            session
+        } else {
+            let s =
+                self.session
+                    .insert(Session::load_with(&self.path, "face.onnx", &self.options)?);
+            self.input_types = s.inputs()?;
+            s
+        };
+        let x = x * 2.0 - 1.0;
+
+        // the fidelity weight is exported either as a scalar or as a [1] tensor,
+        // and not at all by exports with a fixed fidelity
+        let w = self.input_types.get("w");
+        let w_shape = vec![1; w.map_or(0, |w| w.shape.len())];
+        let w_f32 = ndarray::ArrayD::<f32>::from_elem(w_shape.as_slice(), self.fidelity);
+        let w_f64 = ndarray::ArrayD::<f64>::from_elem(w_shape.as_slice(), self.fidelity as f64);
+
+        let mut run = session.prepare();
+        run.set_input("x", &x)?;
+        match w.map(|w| w.elem_type) {
+            None => {}
+            Some(DataType::Float32) => run.set_input("w", &w_f32)?,
+            Some(DataType::Float64) => run.set_input("w", &w_f64)?,
+            Some(t) => {
+                return Err(Error::InvalidInput(format!(
+                    "Unsupported fidelity type: {:?}",
+                    t
+                )))
+            }
+        }
+        let out = run.exec(true)?;
+        let out = out.get_output_idx::<f32, ndarray::Ix4>(0)?;
+        let out = ((out.to_owned() + 1.0) / 2.0).mapv(|v| v.clamp(0.0, 1.0));
+        Ok(out.into_dyn())
+    }
+
+    fn set_fidelity(&mut self, fidelity: f32) {
+        self.fidelity = fidelity.clamp(0.0, 1.0);
+    }
+}
+
+impl Model for CodeFormer {
+    fn unload_model(&mut self) {
+        self.session = None;
+    }
+}
+
+impl CodeFormer {
+    pub fn new(path: impl Into<PathBuf>, options: SessionOptions) -> Result<Self> {
+        Ok(Self {
+            path: path.into(),
+            session: None,
+            options,
+            input_types: HashMap::new(),
+            fidelity: 0.5,
+        })
+    }
+}
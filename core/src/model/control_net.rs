@@ -0,0 +1,66 @@
+use std::{collections::HashMap, path::Path};
+
+use super::{kind::resolve_kind, ldm::control_net, DiffusionScheduleParam, Model};
+use crate::{
+    ort::SessionOptions,
+    result::{Error, Result},
+};
+
+pub trait ControlNet: Model {
+    fn execute(
+        &mut self,
+        hint: &ndarray::ArrayD<f32>,
+        x: &ndarray::ArrayD<f32>,
+        t: &DiffusionScheduleParam,
+        conditions: &HashMap<String, ndarray::ArrayD<f32>>,
+    ) -> Result<HashMap<String, ndarray::ArrayD<f32>>>;
+}
+
+pub struct Control {
+    pub model: Box<dyn ControlNet>,
+    pub hint: ndarray::ArrayD<f32>,
+    pub strength: f32,
+    // active range as a fraction of the sampling progress, 0 being pure noise
+    pub start: f32,
+    pub end: f32,
+}
+
+// Condition that carries the hint of the control at `idx` while sampling, so
+// samplers that window or roll the latent do the same to the hint.
+pub fn hint_key(idx: usize) -> String {
+    format!("hint.{}", idx)
+}
+
+impl Control {
+    pub fn new(model: Box<dyn ControlNet>, hint: ndarray::ArrayD<f32>) -> Self {
+        Self {
+            model,
+            hint,
+            strength: 1.0,
+            start: 0.0,
+            end: 1.0,
+        }
+    }
+
+    pub fn is_active(&self, progress: f32) -> bool {
+        self.start <= progress && progress <= self.end
+    }
+}
+
+pub fn load_control_net(
+    kind: impl AsRef<str>,
+    path: impl AsRef<Path>,
+    options: &SessionOptions,
+) -> Result<Box<dyn ControlNet>> {
+    match resolve_kind(kind.as_ref(), path.as_ref())?.as_str() {
+        "ldm/control-net" => Ok(Box::new(control_net::LdmControlNet::new(
+            path.as_ref(),
+            options.clone(),
+        )?)),
+
+        k => Err(Error::UnsupportedModel(
+            "control net".to_string(),
+            k.to_owned(),
+        )),
+    }
+}
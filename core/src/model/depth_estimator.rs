@@ -0,0 +1,41 @@
+use std::path::Path;
+
+use ndarray::Axis;
+
+use super::{kind::resolve_kind, midas::Midas, Model};
+use crate::{
+    ort::SessionOptions,
+    result::{Error, Result},
+};
+
+pub trait DepthEstimator: Model {
+    // Returns relative inverse depth with shape [b, 1, h, w] matching the input.
+    fn estimate(&mut self, x: &ndarray::ArrayD<f32>) -> Result<ndarray::ArrayD<f32>>;
+}
+
+pub fn load_depth_estimator(
+    kind: impl AsRef<str>,
+    path: impl AsRef<Path>,
+    options: &SessionOptions,
+) -> Result<Box<dyn DepthEstimator>> {
+    match resolve_kind(kind.as_ref(), path.as_ref())?.as_str() {
+        "midas" | "dpt" => Ok(Box::new(Midas::new(path.as_ref(), options.clone())?)),
+
+        k => Err(Error::UnsupportedModel(
+            "depth estimator".to_string(),
+            k.to_owned(),
+        )),
+    }
+}
+
+// Scales each depth map in the batch to [-1, 1], as expected by depth-conditioned UNets.
+pub fn normalize_depth(depth: &ndarray::ArrayD<f32>) -> ndarray::ArrayD<f32> {
+    let mut depth = depth.to_owned();
+    for mut d in depth.axis_iter_mut(Axis(0)) {
+        let min = d.fold(f32::INFINITY, |a, &b| a.min(b));
+        let max = d.fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+        let range = (max - min).max(1e-6);
+        d.mapv_inplace(|v| 2. * (v - min) / range - 1.);
+    }
+    depth
+}
@@ -1,7 +1,13 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
-use super::{ldm::latent_diffusion, Model};
-use crate::result::{Error, Result};
+use super::{kind::resolve_kind, ldm::latent_diffusion, Control, Model};
+use crate::{
+    ort::SessionOptions,
+    result::{Error, Result},
+};
 
 #[derive(Debug)]
 pub struct DiffusionScheduleParam {
@@ -21,12 +27,39 @@ pub trait Diffusion: Model {
         t: &DiffusionScheduleParam,
         conditions: &HashMap<String, ndarray::ArrayD<f32>>,
     ) -> Result<ndarray::ArrayD<f32>>;
+    // Like `execute`, writing into `out` so samplers can reuse it across steps.
+    fn execute_into(
+        &mut self,
+        x: &ndarray::ArrayD<f32>,
+        t: &DiffusionScheduleParam,
+        conditions: &HashMap<String, ndarray::ArrayD<f32>>,
+        out: &mut ndarray::ArrayD<f32>,
+    ) -> Result<()> {
+        *out = self.execute(x, t, conditions)?;
+        Ok(())
+    }
+    fn controls_mut(&mut self) -> Option<&mut Vec<Control>> {
+        None
+    }
+    // Control hints keyed by `hint_key`, at the image size of an h x w latent.
+    fn control_conditions(&self, _h: usize, _w: usize) -> HashMap<String, ndarray::ArrayD<f32>> {
+        HashMap::new()
+    }
+    // Merges LoRA files with their strength into the weights on the next load.
+    fn set_loras(&mut self, _loras: &[(PathBuf, f32)]) -> Result<()> {
+        Err(Error::Unsupported("lora".to_string()))
+    }
 }
 
-pub fn load_diffusion(kind: impl AsRef<str>, path: impl AsRef<Path>) -> Result<Box<dyn Diffusion>> {
-    match kind.as_ref() {
+pub fn load_diffusion(
+    kind: impl AsRef<str>,
+    path: impl AsRef<Path>,
+    options: &SessionOptions,
+) -> Result<Box<dyn Diffusion>> {
+    match resolve_kind(kind.as_ref(), path.as_ref())?.as_str() {
         "ldm/ldm" => Ok(Box::new(latent_diffusion::LatentDiffusion::new(
             path.as_ref(),
+            options.clone(),
         )?)),
 
         k => Err(Error::UnsupportedModel("diffuse".to_string(), k.to_owned())),
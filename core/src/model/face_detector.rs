@@ -0,0 +1,36 @@
+use std::path::Path;
+
+use super::{kind::resolve_kind, retinaface::RetinaFace, Model};
+use crate::{
+    ort::SessionOptions,
+    result::{Error, Result},
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Face {
+    // x1, y1, x2, y2 in pixels
+    pub bbox: [f32; 4],
+    pub score: f32,
+    // eyes, nose and mouth corners, as seen from the viewer left to right
+    pub landmarks: [[f32; 2]; 5],
+}
+
+pub trait FaceDetector: Model {
+    // Detects faces in a single NCHW image in [0, 1].
+    fn detect(&mut self, x: &ndarray::ArrayD<f32>) -> Result<Vec<Face>>;
+}
+
+pub fn load_face_detector(
+    kind: impl AsRef<str>,
+    path: impl AsRef<Path>,
+    options: &SessionOptions,
+) -> Result<Box<dyn FaceDetector>> {
+    match resolve_kind(kind.as_ref(), path.as_ref())?.as_str() {
+        "retinaface" => Ok(Box::new(RetinaFace::new(path.as_ref(), options.clone())?)),
+
+        k => Err(Error::UnsupportedModel(
+            "face detector".to_string(),
+            k.to_owned(),
+        )),
+    }
+}
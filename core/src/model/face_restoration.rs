@@ -0,0 +1,36 @@
+use std::path::Path;
+
+use super::{codeformer::CodeFormer, gfpgan::Gfpgan, kind::resolve_kind, Model};
+use crate::{
+    ort::SessionOptions,
+    result::{Error, Result},
+};
+
+pub trait FaceRestoration: Model {
+    // Restores a batch of aligned face crops in [0, 1] at the model resolution.
+    fn restore(&mut self, x: &ndarray::ArrayD<f32>) -> Result<ndarray::ArrayD<f32>>;
+
+    // Side length of the aligned crops the model expects.
+    fn face_size(&self) -> usize {
+        512
+    }
+
+    // Trades quality (0) for fidelity to the input (1), if the model supports it.
+    fn set_fidelity(&mut self, _fidelity: f32) {}
+}
+
+pub fn load_face_restoration(
+    kind: impl AsRef<str>,
+    path: impl AsRef<Path>,
+    options: &SessionOptions,
+) -> Result<Box<dyn FaceRestoration>> {
+    match resolve_kind(kind.as_ref(), path.as_ref())?.as_str() {
+        "gfpgan" => Ok(Box::new(Gfpgan::new(path.as_ref(), options.clone())?)),
+        "codeformer" => Ok(Box::new(CodeFormer::new(path.as_ref(), options.clone())?)),
+
+        k => Err(Error::UnsupportedModel(
+            "face restoration".to_string(),
+            k.to_owned(),
+        )),
+    }
+}
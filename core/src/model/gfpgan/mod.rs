@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+
+use super::{FaceRestoration, Model};
+use crate::{
+    ort::{Session, SessionOptions},
+    result::Result,
+};
+
+pub struct Gfpgan {
+    path: PathBuf,
+    session: Option<Session>,
+    options: SessionOptions,
+}
+
+impl FaceRestoration for Gfpgan {
+    fn restore(&mut self, x: &ndarray::ArrayD<f32>) -> Result<ndarray::ArrayD<f32>> {
+        if x.is_empty() {
+            return Ok(ndarray::ArrayD::zeros([].as_slice()));
+        }
+
+        let session = if let Some(session) = &self.session {
+            session
+        } else {
+            self.session
+                .insert(Session::load_with(&self.path, "face.onnx", &self.options)?)
+        };
+        let x = x * 2.0 - 1.0;
+
+        let mut run = session.prepare();
+        run.set_input("input", &x)?;
+        let out = run.exec(true)?;
+        let out = out.get_output_idx::<f32, ndarray::Ix4>(0)?;
+        let out = ((out.to_owned() + 1.0) / 2.0).mapv(|v| v.clamp(0.0, 1.0));
+        Ok(out.into_dyn())
+    }
+}
+
+impl Model for Gfpgan {
+    fn unload_model(&mut self) {
+        self.session = None;
+    }
+}
+
+impl Gfpgan {
+    pub fn new(path: impl Into<PathBuf>, options: SessionOptions) -> Result<Self> {
+        Ok(Self {
+            path: path.into(),
+            session: None,
+            options,
+        })
+    }
+}
@@ -0,0 +1,29 @@
+use std::path::Path;
+
+use super::{clip, kind::resolve_kind, Model};
+use crate::{
+    ort::SessionOptions,
+    result::{Error, Result},
+};
+
+pub trait ImageEncoder: Model {
+    fn encode(&mut self, x: &ndarray::ArrayD<f32>) -> Result<ndarray::ArrayD<f32>>;
+}
+
+pub fn load_image_encoder(
+    kind: impl AsRef<str>,
+    path: impl AsRef<Path>,
+    options: &SessionOptions,
+) -> Result<Box<dyn ImageEncoder>> {
+    match resolve_kind(kind.as_ref(), path.as_ref())?.as_str() {
+        "clip" => Ok(Box::new(clip::ClipImageEncoder::new(
+            path.as_ref(),
+            options.clone(),
+        )?)),
+
+        k => Err(Error::UnsupportedModel(
+            "image encoder".to_string(),
+            k.to_owned(),
+        )),
+    }
+}
@@ -0,0 +1,147 @@
+use std::{io::Read, path::Path};
+
+use serde::Deserialize;
+
+use crate::{
+    ort::{read_graph_metadata, GraphOptimizationLevel, Session, SessionOptions},
+    result::{Error, Result},
+};
+
+// Words in the graph name, producer or description that identify a model.
+// They match whole words only, so "dpt" doesn't match inside another word.
+const KEYWORDS: &[(&str, &str)] = &[
+    ("codeformer", "codeformer"),
+    ("gfpgan", "gfpgan"),
+    ("gfpganv1", "gfpgan"),
+    ("swinir", "swinir"),
+    ("esrgan", "esrgan"),
+    ("realesrgan", "esrgan"),
+    ("retinaface", "retinaface"),
+    ("midas", "midas"),
+    ("dpt", "dpt"),
+    ("blip", "blip"),
+];
+
+// Graphs looked at first, so an upscaler is told by its ldm.onnx rather than
+// the decoder it ships with. Other graphs follow.
+const GRAPH_ORDER: &[&str] = &["ldm.onnx", "visual.onnx", "decoder.onnx", "encoder.onnx"];
+
+// Kind of the model at `path`, taken from the "kind" in metadata.json, or
+// otherwise inferred from the ONNX metadata and signature of its graphs.
+pub fn detect_kind(path: impl AsRef<Path>) -> Result<String> {
+    let path = path.as_ref();
+    if let Some(kind) = declared_kind(path)? {
+        return Ok(kind);
+    }
+
+    let mut graphs = if path.is_dir() {
+        std::fs::read_dir(path)?
+            .map(|e| Ok(e?.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        tsar::Archive::new(std::fs::File::open(path)?)?
+            .file_names()
+            .map(|s| s.to_owned())
+            .collect()
+    };
+    graphs.retain(|f| f.ends_with(".onnx") && !f.starts_with('.'));
+    graphs.sort_by_key(|g| {
+        GRAPH_ORDER
+            .iter()
+            .position(|o| o == g)
+            .unwrap_or(GRAPH_ORDER.len())
+    });
+
+    for g in graphs {
+        if let Some(kind) = graph_kind(path, &g)? {
+            return Ok(kind);
+        }
+    }
+    Err(Error::InvalidInput(format!(
+        "cannot tell the kind of model {:?}",
+        path
+    )))
+}
+
+// Lets loaders take "auto" for the kind.
+pub(crate) fn resolve_kind(kind: &str, path: &Path) -> Result<String> {
+    if kind == "auto" {
+        detect_kind(path)
+    } else {
+        Ok(kind.to_string())
+    }
+}
+
+fn declared_kind(path: &Path) -> Result<Option<String>> {
+    #[derive(Deserialize)]
+    struct Metadata {
+        kind: Option<String>,
+    }
+
+    let metadata_json = if path.is_dir() {
+        match std::fs::read_to_string(path.join("metadata.json")) {
+            Ok(s) => s,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+    } else {
+        let mut ar = tsar::Archive::new(std::fs::File::open(path)?)?;
+        if !ar.file_names().any(|f| f == "metadata.json") {
+            return Ok(None);
+        }
+        let mut buf = String::new();
+        ar.file_by_name("metadata.json")?.read_to_string(&mut buf)?;
+        buf
+    };
+    let metadata: Metadata = serde_json::from_str(&metadata_json)?;
+    Ok(metadata.kind)
+}
+
+// Kind from the metadata and inputs in the ONNX file. A session is created
+// only for a graph the protobuf reader can't make sense of.
+fn graph_kind(path: &Path, graph: &str) -> Result<Option<String>> {
+    let (meta, inputs) = match read_graph_metadata(path, graph) {
+        Ok(m) => m,
+        Err(_) => {
+            // the graph is only opened for its metadata, so skip optimizing it
+            let options =
+                SessionOptions::cpu().with_optimization_level(GraphOptimizationLevel::Disabled);
+            let session = Session::load_with(path, graph, &options)?;
+            let inputs = session
+                .signature()
+                .inputs
+                .iter()
+                .map(|(n, _)| n.clone())
+                .collect();
+            (session.metadata()?, inputs)
+        }
+    };
+    if let Some(kind) = meta.custom.get("kind") {
+        return Ok(Some(kind.clone()));
+    }
+    let text = format!("{} {} {}", meta.graph_name, meta.producer, meta.description).to_lowercase();
+    let words: Vec<&str> = text
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect();
+    if let Some((_, kind)) = KEYWORDS.iter().find(|(k, _)| words.contains(k)) {
+        return Ok(Some(kind.to_string()));
+    }
+
+    // graphs only one kind of model ships; the others, like sr.onnx, stay ambiguous
+    let kind = match graph {
+        "ldm.onnx" if inputs.iter().any(|i| i == "noise_level") => "ldm/upscaler",
+        "ldm.onnx" => "ldm/ldm",
+        "control.onnx" => "ldm/control-net",
+        "encoder.onnx" | "decoder.onnx" => "ldm/vq",
+        "bert.onnx" => "ldm/bert",
+        "textual.onnx" => "clip",
+        "aesthetic.onnx" => "clip/aesthetic",
+        "safety_checker.onnx" => "clip/safety-checker",
+        "text_decoder.onnx" => "blip",
+        "depth.onnx" => "midas",
+        "detector.onnx" => "retinaface",
+        _ => return Ok(None),
+    };
+    Ok(Some(kind.to_string()))
+}
@@ -4,7 +4,7 @@ use tokenizers::Tokenizer;
 
 use crate::{
     model::{text_encoder::TextEncoder, Model},
-    ort::Session,
+    ort::{Session, SessionOptions},
     result::{Error, Result},
 };
 
@@ -12,6 +12,7 @@ pub struct BertEncoder {
     tokenizer: Tokenizer,
     path: PathBuf,
     session: Option<Session>,
+    options: SessionOptions,
 }
 
 impl TextEncoder for BertEncoder {
@@ -31,7 +32,7 @@ impl TextEncoder for BertEncoder {
             session
         } else {
             self.session
-                .insert(Session::load(&self.path, "bert.onnx", true)?)
+                .insert(Session::load_with(&self.path, "bert.onnx", &self.options)?)
         };
 
         let enc = ndarray::Array2::from_shape_vec(
@@ -58,7 +59,7 @@ impl Model for BertEncoder {
 }
 
 impl BertEncoder {
-    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+    pub fn new(path: impl Into<PathBuf>, options: SessionOptions) -> Result<Self> {
         let path = path.into();
         let tokenizer_json = if path.is_dir() {
             std::fs::read_to_string(path.join("tokenizer.json"))?
@@ -86,6 +87,7 @@ impl BertEncoder {
             tokenizer,
             path,
             session: None,
+            options,
         })
     }
 }
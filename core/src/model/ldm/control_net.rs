@@ -0,0 +1,106 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::{
+    model::{ControlNet, DiffusionScheduleParam, Model},
+    ort::{DataType, Session, SessionOptions, TensorInfo},
+    result::{Error, Result},
+};
+
+pub struct LdmControlNet {
+    path: PathBuf,
+    session: Option<Session>,
+    options: SessionOptions,
+    input_types: HashMap<String, TensorInfo>,
+}
+
+impl ControlNet for LdmControlNet {
+    fn execute(
+        &mut self,
+        hint: &ndarray::ArrayD<f32>,
+        x: &ndarray::ArrayD<f32>,
+        t: &DiffusionScheduleParam,
+        conditions: &HashMap<String, ndarray::ArrayD<f32>>,
+    ) -> Result<HashMap<String, ndarray::ArrayD<f32>>> {
+        if x.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let session = if let Some(session) = &self.session {
+            session
+        } else {
+            let s = self.session.insert(Session::load_with(
+                &self.path,
+                "control.onnx",
+                &self.options,
+            )?);
+            self.input_types = s.inputs()?;
+            s
+        };
+
+        let batch = x.shape()[0];
+        let hint = if hint.shape()[0] == batch {
+            hint.to_owned()
+        } else {
+            let mut shape = hint.shape().to_vec();
+            shape[0] = batch;
+            hint.broadcast(shape.as_slice())
+                .ok_or_else(|| {
+                    Error::InvalidInput(format!(
+                        "hint of shape {:?} does not match batch size {}",
+                        hint.shape(),
+                        batch
+                    ))
+                })?
+                .to_owned()
+        };
+
+        let t_f32 = ndarray::Array1::<f32>::from_elem((batch,), t.timestep as f32);
+        let t_i64 = ndarray::Array1::<i64>::from_elem((batch,), t.timestep as i64);
+
+        let mut run = session.prepare();
+        run.set_input("x", x)?;
+        run.set_input("hint", &hint)?;
+        match self.input_types["t"].elem_type {
+            DataType::Float32 => run.set_input("t", &t_f32)?,
+            DataType::Int64 => run.set_input("t", &t_i64)?,
+            t => {
+                return Err(Error::InvalidInput(format!(
+                    "Unsupported time type: {:?}",
+                    t
+                )))
+            }
+        }
+        for (k, v) in conditions {
+            if self.input_types.get(k).is_some() {
+                run.set_input(k, v)?;
+            }
+        }
+
+        let ret = run.exec(t.timestep == 1)?;
+        session
+            .outputs()?
+            .into_iter()
+            .map(|name| {
+                let v = ret.get_output::<f32, ndarray::IxDyn>(&name)?.to_owned();
+                Ok((name, v))
+            })
+            .collect()
+    }
+}
+
+impl Model for LdmControlNet {
+    fn unload_model(&mut self) {
+        self.session = None;
+    }
+}
+
+impl LdmControlNet {
+    pub fn new(path: impl Into<PathBuf>, options: SessionOptions) -> Result<Self> {
+        Ok(Self {
+            path: path.into(),
+            session: None,
+            options,
+            input_types: HashMap::new(),
+        })
+    }
+}
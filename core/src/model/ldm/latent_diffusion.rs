@@ -1,13 +1,22 @@
-use std::{collections::HashMap, io::Read, path::PathBuf};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io::Read,
+    path::PathBuf,
+    sync::Arc,
+};
 
+use half::{bf16, f16};
 use ndarray::{Array, Axis};
 use ndarray_rand::{rand_distr::Normal, RandomExt};
 use serde::Deserialize;
 use smallvec::SmallVec;
 
 use crate::{
-    model::{Diffusion, DiffusionScheduleParam, Model},
-    ort::{DataType, Session, TensorInfo},
+    imgproc,
+    model::{
+        hint_key, Control, Diffusion, DiffusionScheduleParam, LoraTarget, LoraTransform, Model,
+    },
+    ort::{DataType, InitializerTransform, Session, SessionOptions, TensorInfo},
     result::{Error, Result},
 };
 
@@ -15,7 +24,38 @@ pub struct LatentDiffusion {
     metadata: Metadata,
     path: PathBuf,
     session: Option<Session>,
+    options: SessionOptions,
     input_types: HashMap<String, TensorInfo>,
+    output_type: DataType,
+    // buffers for fp16 and bf16 models, reused across steps like the f32 output
+    output_f16: ndarray::ArrayD<f16>,
+    output_bf16: ndarray::ArrayD<bf16>,
+    // conditions converted to the model's precision, with the values they came
+    // from, so they convert once rather than on every step. Samplers alternate
+    // the conditional and unconditional values, and both are kept.
+    converted_conditions: HashMap<String, Vec<(ndarray::ArrayD<f32>, Converted)>>,
+    controls: Vec<Control>,
+    // residual shapes of the controls by the shape of x, without the batch
+    residual_shapes: HashMap<Vec<usize>, HashMap<String, Vec<usize>>>,
+    lora: Option<Arc<LoraTransform>>,
+}
+
+// Inputs are computed in f32 and converted to the precision the model declares.
+enum Converted {
+    F16(ndarray::ArrayD<f16>),
+    Bf16(ndarray::ArrayD<bf16>),
+    I64(ndarray::ArrayD<i64>),
+}
+
+impl Converted {
+    fn new(elem_type: DataType, v: &ndarray::ArrayD<f32>) -> Option<Self> {
+        match elem_type {
+            DataType::Float16 => Some(Converted::F16(v.mapv(f16::from_f32))),
+            DataType::Bfloat16 => Some(Converted::Bf16(v.mapv(bf16::from_f32))),
+            DataType::Int64 => Some(Converted::I64(v.mapv(|v| v.round() as i64))),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -25,6 +65,11 @@ struct Metadata {
     num_channels: Option<usize>,
     image_scale: Option<usize>,
     timesteps: usize,
+    // conditions concatenated to the latent as extra channels
+    concat_conditions: Option<Vec<String>>,
+    lora_keys: Option<HashMap<String, LoraTarget>>,
+    // "eps" (default) or "v"; v predictions are converted to eps for the samplers
+    parameterization: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -131,34 +176,29 @@ impl Diffusion for LatentDiffusion {
         t: &DiffusionScheduleParam,
         conditions: &std::collections::HashMap<String, ndarray::ArrayD<f32>>,
     ) -> Result<ndarray::ArrayD<f32>> {
+        let mut out = ndarray::ArrayD::zeros([].as_slice());
+        self.execute_into(x, t, conditions, &mut out)?;
+        Ok(out)
+    }
+
+    fn execute_into(
+        &mut self,
+        x: &ndarray::ArrayD<f32>,
+        t: &DiffusionScheduleParam,
+        conditions: &std::collections::HashMap<String, ndarray::ArrayD<f32>>,
+        out: &mut ndarray::ArrayD<f32>,
+    ) -> Result<()> {
         if x.is_empty() {
-            return Ok(ndarray::ArrayD::zeros([].as_slice()));
+            *out = ndarray::ArrayD::zeros([].as_slice());
+            return Ok(());
         }
 
-        let session = if let Some(session) = &self.session {
-            session
-        } else {
-            let s = self
-                .session
-                .insert(Session::load(&self.path, "ldm.onnx", false)?);
-            self.input_types = s.inputs()?;
-            s
-        };
+        self.load()?;
+        let session = self.session.as_ref().unwrap();
 
-        enum TimeInput {
-            F32(ndarray::Array1<f32>),
-            I64(ndarray::Array1<i64>),
-        }
-        let ti: TimeInput = match self.input_types["t"].elem_type {
-            DataType::Float32 => {
-                let mut tt = ndarray::Array1::<f32>::zeros((x.shape()[0],));
-                tt.fill(t.timestep as f32);
-                TimeInput::F32(tt)
-            }
-            DataType::Int64 => {
-                let mut tt = ndarray::Array1::<i64>::zeros((x.shape()[0],));
-                tt.fill(t.timestep as i64);
-                TimeInput::I64(tt)
+        let ti = match self.input_types["t"].elem_type {
+            DataType::Float32 | DataType::Float16 | DataType::Bfloat16 | DataType::Int64 => {
+                ndarray::ArrayD::<f32>::from_elem([x.shape()[0]].as_slice(), t.timestep as f32)
             }
             t => {
                 return Err(Error::InvalidInput(format!(
@@ -170,11 +210,81 @@ impl Diffusion for LatentDiffusion {
 
         let mut temp: HashMap<String, ndarray::ArrayD<f32>> = HashMap::new();
 
-        let mut run = session.prepare();
-        run.set_input("x", x)?;
-        match &ti {
-            TimeInput::F32(t) => run.set_input("t", t)?,
-            TimeInput::I64(t) => run.set_input("t", t)?,
+        let default_concat = ["depth".to_string()];
+        let concat_keys = self
+            .metadata
+            .concat_conditions
+            .as_deref()
+            .unwrap_or(&default_concat);
+        let extra: Vec<_> = concat_keys
+            .iter()
+            .filter_map(|k| conditions.get(k))
+            .map(|v| imgproc::resize(v, x.shape()[2], x.shape()[3]))
+            .collect();
+        let x_in = if extra.is_empty() {
+            None
+        } else {
+            let views: Vec<_> = Some(x.view())
+                .into_iter()
+                .chain(extra.iter().map(|v| v.view()))
+                .collect();
+            Some(
+                ndarray::concatenate(Axis(1), &views)
+                    .map_err(|e| Error::InvalidInput(format!("invalid concat condition: {}", e)))?,
+            )
+        };
+
+        let progress = 1. - t.timestep as f32 / self.metadata.timesteps as f32;
+        let mut residuals: HashMap<String, ndarray::ArrayD<f32>> = HashMap::new();
+        let residual_shapes = self
+            .residual_shapes
+            .entry(x.shape()[1..].to_vec())
+            .or_default();
+        let known = !residual_shapes.is_empty();
+        let scale = self.metadata.image_scale.unwrap_or(8);
+        let (hint_h, hint_w) = (x.shape()[2] * scale, x.shape()[3] * scale);
+        for (i, control) in self.controls.iter_mut().enumerate() {
+            // inactive controls still run once per shape of x so the residual shapes are known
+            let strength = if control.is_active(progress) {
+                control.strength
+            } else if !known {
+                0.
+            } else {
+                continue;
+            };
+            // samplers pass the hint windowed or rolled along with the latent
+            let resized;
+            let hint = match conditions.get(&hint_key(i)) {
+                Some(hint) => hint,
+                None if control.hint.shape()[2..] == [hint_h, hint_w] => &control.hint,
+                None => {
+                    resized = imgproc::resize(&control.hint, hint_h, hint_w);
+                    &resized
+                }
+            };
+            for (k, v) in control.model.execute(hint, x, t, conditions)? {
+                if !self.input_types.contains_key(&k) {
+                    continue;
+                }
+                residual_shapes.insert(k.clone(), v.shape()[1..].to_vec());
+                match residuals.entry(k) {
+                    Entry::Occupied(mut e) => {
+                        e.get_mut().zip_mut_with(&v, |a, b| *a += b * strength)
+                    }
+                    Entry::Vacant(e) => {
+                        e.insert(v * strength);
+                    }
+                }
+            }
+        }
+        for (k, shape) in residual_shapes.iter() {
+            if !residuals.contains_key(k) {
+                let shape: SmallVec<[usize; 4]> = Some(x.shape()[0])
+                    .into_iter()
+                    .chain(shape.iter().copied())
+                    .collect();
+                residuals.insert(k.clone(), ndarray::ArrayD::zeros(shape.as_slice()));
+            }
         }
 
         if self.input_types.get("img").is_some() {
@@ -184,6 +294,9 @@ impl Diffusion for LatentDiffusion {
 
         if let Some(true) = self.metadata.normalize_condition {
             for (k, v) in conditions {
+                if concat_keys.contains(k) || !self.input_types.contains_key(k) {
+                    continue;
+                }
                 let mut norm = v.mapv(|v| v * v);
                 while norm.shape().len() > 1 {
                     norm = norm.sum_axis(Axis(norm.ndim() - 1));
@@ -223,36 +336,187 @@ impl Diffusion for LatentDiffusion {
                 }
             }
         }
+        let mut inputs: Vec<(&str, &ndarray::ArrayD<f32>)> =
+            vec![("x", x_in.as_ref().unwrap_or(x)), ("t", &ti)];
         for (k, v) in conditions {
-            if self.input_types.get(k).is_some() {
-                if let Some(vv) = temp.get(k) {
-                    run.set_input(k, vv)?;
-                } else {
-                    run.set_input(k, v)?;
-                }
+            if self.input_types.contains_key(k) {
+                inputs.push((k, temp.get(k).unwrap_or(v)));
             }
         }
-
         for (k, v) in &temp {
             if let Some(k) = k.strip_prefix('_') {
-                run.set_input(k, v)?;
+                inputs.push((k, v));
+            }
+        }
+        for (k, v) in &residuals {
+            inputs.push((k, v));
+        }
+
+        // conditions stay the same across steps and keep their conversion, unlike
+        // x, t and the residuals
+        let mut converted: HashMap<&str, Converted> = HashMap::new();
+        let mut cached: HashMap<&str, usize> = HashMap::new();
+        for (k, v) in &inputs {
+            let elem_type = match self.input_types.get(*k) {
+                Some(t) => t.elem_type,
+                None => continue,
+            };
+            match conditions.get(*k) {
+                Some(c) => {
+                    let entries = self.converted_conditions.entry(k.to_string()).or_default();
+                    let idx = match entries.iter().position(|(src, _)| src == c) {
+                        Some(idx) => idx,
+                        None => match Converted::new(elem_type, v) {
+                            Some(cv) => {
+                                entries.insert(0, (c.clone(), cv));
+                                entries.truncate(2);
+                                0
+                            }
+                            None => continue,
+                        },
+                    };
+                    cached.insert(k, idx);
+                }
+                None => {
+                    if let Some(cv) = Converted::new(elem_type, v) {
+                        converted.insert(k, cv);
+                    }
+                }
+            }
+        }
+
+        let mut binding = session.bind()?;
+        for (k, v) in &inputs {
+            let c = match cached.get(k) {
+                Some(&idx) => Some(&self.converted_conditions[*k][idx].1),
+                None => converted.get(k),
+            };
+            match c {
+                Some(Converted::F16(v)) => binding.bind_input(k, v)?,
+                Some(Converted::Bf16(v)) => binding.bind_input(k, v)?,
+                Some(Converted::I64(v)) => binding.bind_input(k, v)?,
+                None => binding.bind_input(k, *v)?,
+            }
+        }
+
+        // the prediction has the shape of the latent, so the buffer survives across steps
+        if out.shape() != x.shape() {
+            *out = ndarray::ArrayD::zeros(x.shape());
+        }
+        let output = session.outputs()?.swap_remove(0);
+        match self.output_type {
+            DataType::Float32 => {
+                binding.bind_output(output, out)?;
+                binding.run(t.timestep == 1)?;
+            }
+            DataType::Float16 => {
+                if self.output_f16.shape() != x.shape() {
+                    self.output_f16 = ndarray::ArrayD::from_elem(x.shape(), f16::ZERO);
+                }
+                binding.bind_output(output, &mut self.output_f16)?;
+                binding.run(t.timestep == 1)?;
+                drop(binding);
+                out.zip_mut_with(&self.output_f16, |o, h| *o = h.to_f32());
+            }
+            DataType::Bfloat16 => {
+                if self.output_bf16.shape() != x.shape() {
+                    self.output_bf16 = ndarray::ArrayD::from_elem(x.shape(), bf16::ZERO);
+                }
+                binding.bind_output(output, &mut self.output_bf16)?;
+                binding.run(t.timestep == 1)?;
+                drop(binding);
+                out.zip_mut_with(&self.output_bf16, |o, h| *o = h.to_f32());
+            }
+            t => {
+                return Err(Error::InvalidInput(format!(
+                    "Unsupported output type: {:?}",
+                    t
+                )))
+            }
+        }
+
+        match self.metadata.parameterization.as_deref() {
+            None | Some("eps") => Ok(()),
+            Some("v") => {
+                let a = t.alpha_cumprod.sqrt() as f32;
+                let b = (1. - t.alpha_cumprod).sqrt() as f32;
+                out.zip_mut_with(x, |y, x| *y = *y * a + x * b);
+                Ok(())
             }
+            Some(p) => Err(Error::Unsupported(format!("parameterization {}", p))),
         }
+    }
+
+    fn control_conditions(&self, h: usize, w: usize) -> HashMap<String, ndarray::ArrayD<f32>> {
+        let (h, w) = (h * self.image_scale(), w * self.image_scale());
+        self.controls
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (hint_key(i), imgproc::resize(&c.hint, h, w)))
+            .collect()
+    }
+
+    fn controls_mut(&mut self) -> Option<&mut Vec<Control>> {
+        // the controls may change, and with them the residuals
+        self.residual_shapes.clear();
+        Some(&mut self.controls)
+    }
 
-        let ret = run.exec(t.timestep == 1)?;
-        let y = ret.get_output_idx::<f32, ndarray::Ix4>(0)?;
-        Ok(y.into_dyn().to_owned())
+    fn set_loras(&mut self, loras: &[(PathBuf, f32)]) -> Result<()> {
+        self.lora = if loras.is_empty() {
+            None
+        } else {
+            let keys = self
+                .metadata
+                .lora_keys
+                .as_ref()
+                .ok_or_else(|| Error::Unsupported("model has no lora key table".to_string()))?;
+            Some(Arc::new(LoraTransform::new(loras, keys)?))
+        };
+        self.session = None;
+        Ok(())
     }
 }
 
 impl Model for LatentDiffusion {
     fn unload_model(&mut self) {
         self.session = None;
+        self.controls
+            .iter_mut()
+            .for_each(|c| c.model.unload_model());
     }
 }
 
 impl LatentDiffusion {
-    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+    fn load(&mut self) -> Result<()> {
+        if self.session.is_some() {
+            return Ok(());
+        }
+        let transforms: Vec<Arc<dyn InitializerTransform>> = self
+            .lora
+            .iter()
+            .map(|l| l.clone() as Arc<dyn InitializerTransform>)
+            .collect();
+        let s = self.session.insert(Session::load_transformed(
+            &self.path,
+            "ldm.onnx",
+            &self.options,
+            &transforms,
+        )?);
+        self.input_types = s.inputs()?;
+        let output = s.outputs()?.swap_remove(0);
+        self.output_type = s.output_types()?[&output].elem_type;
+        self.converted_conditions.clear();
+        Ok(())
+    }
+
+    // Type of the model input `name`, loading the model to read it.
+    pub(crate) fn input_type(&mut self, name: &str) -> Result<Option<TensorInfo>> {
+        self.load()?;
+        Ok(self.input_types.get(name).cloned())
+    }
+
+    pub fn new(path: impl Into<PathBuf>, options: SessionOptions) -> Result<Self> {
         let path = path.into();
         let metadata_json = if path.is_dir() {
             std::fs::read_to_string(path.join("metadata.json"))?
@@ -269,7 +533,15 @@ impl LatentDiffusion {
             metadata,
             path,
             session: None,
+            options,
             input_types: HashMap::new(),
+            output_type: DataType::Float32,
+            output_f16: ndarray::ArrayD::from_elem([].as_slice(), f16::ZERO),
+            output_bf16: ndarray::ArrayD::from_elem([].as_slice(), bf16::ZERO),
+            converted_conditions: HashMap::new(),
+            controls: Vec::new(),
+            residual_shapes: HashMap::new(),
+            lora: None,
         })
     }
 }
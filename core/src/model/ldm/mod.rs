@@ -1,3 +1,5 @@
 pub mod bert;
+pub mod control_net;
 pub mod latent_diffusion;
+pub mod upscaler;
 pub mod vq;
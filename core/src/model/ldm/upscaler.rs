@@ -0,0 +1,189 @@
+use std::{collections::HashMap, io::Read, path::PathBuf};
+
+use ndarray::{Array, Axis, Slice};
+use ndarray_rand::{rand_distr::Normal, RandomExt};
+use serde::Deserialize;
+
+use super::{latent_diffusion::LatentDiffusion, vq::Vq};
+use crate::{
+    model::{AutoEncoder, Diffusion, Model, SuperResolution},
+    ort::{Dim, SessionOptions},
+    result::{Error, Result},
+    sampler::LmsSampler,
+};
+
+// schedule of the noise added to the low resolution input
+const NOISE_BETA_START: f64 = 0.0001;
+const NOISE_BETA_END: f64 = 0.02;
+const NOISE_TIMESTEPS: usize = 1000;
+
+// Text-guided diffusion upscaler such as the SD x4 upscaler. The archive holds
+// the unet (ldm.onnx) and the autoencoder (encoder.onnx, decoder.onnx), and its
+// metadata.json is shared by both, with `concat_conditions` set to ["low_res"].
+pub struct DiffusionUpscaler {
+    metadata: Metadata,
+    diffusion: LatentDiffusion,
+    autoencoder: Vq,
+    condition: HashMap<String, ndarray::ArrayD<f32>>,
+    uncondition: HashMap<String, ndarray::ArrayD<f32>>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct Metadata {
+    scale: usize,
+    steps: usize,
+    // noise added to the input and passed to the model as `noise_level`
+    noise_level: usize,
+    // concatenate latents of the input instead of its pixels, for latent upscalers
+    encode_input: bool,
+    // text conditions the unet takes, out of those the pipeline provides
+    text_conditions: Vec<String>,
+    guidance_scale: f32,
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Self {
+            scale: 4,
+            steps: 25,
+            noise_level: 20,
+            encode_input: false,
+            text_conditions: vec!["c".to_string()],
+            guidance_scale: 7.5,
+        }
+    }
+}
+
+impl SuperResolution for DiffusionUpscaler {
+    fn execute(&mut self, x: &ndarray::ArrayD<f32>) -> Result<ndarray::ArrayD<f32>> {
+        if x.is_empty() {
+            return Ok(ndarray::ArrayD::zeros([].as_slice()));
+        }
+        if let Some(k) = self
+            .metadata
+            .text_conditions
+            .iter()
+            .find(|k| !self.condition.contains_key(*k))
+        {
+            return Err(Error::InvalidInput(format!(
+                "diffusion upscaler needs the text condition {:?}",
+                k
+            )));
+        }
+        // the prompt must come from the text encoder the unet was trained with
+        for k in &self.metadata.text_conditions {
+            let width = self.condition[k].shape().last().copied();
+            if let Some(t) = self.diffusion.input_type(k)? {
+                if let (Some(Dim::Fixed(want)), Some(got)) = (t.shape.last(), width) {
+                    if *want != got {
+                        return Err(Error::InvalidInput(format!(
+                            "text condition {:?} has width {}, but the upscaler takes {}",
+                            k, got, want
+                        )));
+                    }
+                }
+            }
+        }
+
+        let out = (0..x.shape()[0])
+            .map(|i| self.upscale(&x.slice_axis(Axis(0), Slice::from(i..i + 1)).to_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        let views: Vec<_> = out.iter().map(|v| v.view()).collect();
+        ndarray::concatenate(Axis(0), &views)
+            .map_err(|e| Error::InvalidInput(format!("invalid upscaler output: {}", e)))
+    }
+
+    fn set_text_condition(
+        &mut self,
+        condition: &HashMap<String, ndarray::ArrayD<f32>>,
+        uncondition: &HashMap<String, ndarray::ArrayD<f32>>,
+    ) {
+        let declared = |c: &HashMap<String, ndarray::ArrayD<f32>>| {
+            c.iter()
+                .filter(|(k, _)| self.metadata.text_conditions.contains(k))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        };
+        self.condition = declared(condition);
+        self.uncondition = declared(uncondition);
+    }
+}
+
+impl Model for DiffusionUpscaler {
+    fn unload_model(&mut self) {
+        self.diffusion.unload_model();
+        self.autoencoder.unload_model();
+    }
+}
+
+impl DiffusionUpscaler {
+    pub fn new(path: impl Into<PathBuf>, options: SessionOptions) -> Result<Self> {
+        let path = path.into();
+        let metadata_json = if path.is_dir() {
+            std::fs::read_to_string(path.join("metadata.json"))?
+        } else {
+            let mut ar = tsar::Archive::new(std::fs::File::open(&path)?)?;
+            let mut buf = String::new();
+            ar.file_by_name("metadata.json")?.read_to_string(&mut buf)?;
+            buf
+        };
+
+        let metadata: Metadata = serde_json::from_str(&metadata_json)?;
+
+        Ok(Self {
+            metadata,
+            diffusion: LatentDiffusion::new(&path, options.clone())?,
+            autoencoder: Vq::new(&path, options)?,
+            condition: HashMap::new(),
+            uncondition: HashMap::new(),
+        })
+    }
+
+    fn upscale(&mut self, x: &ndarray::ArrayD<f32>) -> Result<ndarray::ArrayD<f32>> {
+        let (h, w) = (x.shape()[2], x.shape()[3]);
+        let low_res = if self.metadata.encode_input {
+            self.autoencoder.encode(x)?
+        } else {
+            x * 2.0 - 1.0
+        };
+
+        let alpha_cumprod = noise_alpha_cumprod(self.metadata.noise_level);
+        let noise = Array::random(low_res.raw_dim(), Normal::new(0.0, 1.0).unwrap());
+        let low_res =
+            low_res * alpha_cumprod.sqrt() as f32 + noise * (1. - alpha_cumprod).sqrt() as f32;
+        let noise_level =
+            ndarray::ArrayD::from_elem([1].as_slice(), self.metadata.noise_level as f32);
+
+        let mut cond = self.condition.clone();
+        let mut uncond = self.uncondition.clone();
+        for c in [&mut cond, &mut uncond] {
+            c.insert("low_res".to_string(), low_res.clone());
+            c.insert("noise_level".to_string(), noise_level.clone());
+        }
+
+        let sched = self.diffusion.make_schedule(self.metadata.steps);
+        let noise = self
+            .diffusion
+            .make_noise(1, w * self.metadata.scale, h * self.metadata.scale);
+        let latent = {
+            let mut d = LmsSampler::new(&mut self.diffusion, &sched, cond, uncond, noise);
+            d.guidance_scale = self.metadata.guidance_scale;
+            for i in 0..sched.len() {
+                d.next(i);
+            }
+            d.seed
+        };
+
+        self.autoencoder.decode(&latent)
+    }
+}
+
+fn noise_alpha_cumprod(level: usize) -> f64 {
+    (0..=level.min(NOISE_TIMESTEPS - 1))
+        .map(|i| {
+            1. - (NOISE_BETA_START
+                + (NOISE_BETA_END - NOISE_BETA_START) * i as f64 / (NOISE_TIMESTEPS - 1) as f64)
+        })
+        .product()
+}
@@ -1,18 +1,26 @@
 use std::{io::Read, path::PathBuf};
 
+use ndarray::{Axis, Slice};
 use serde::Deserialize;
 
 use crate::{
     model::{AutoEncoder, Model},
-    ort::Session,
-    result::Result,
+    ort::{Dim, Session, SessionOptions},
+    result::{Error, Result},
+    tile::{self, TileConfig, Tiling},
 };
 
+const LATENT_SCALE: usize = 8;
+const SEAMLESS_PAD: usize = 8;
+
 pub struct Vq {
     metadata: Metadata,
     path: PathBuf,
     encoder_session: Option<Session>,
     decoder_session: Option<Session>,
+    options: SessionOptions,
+    tiling: Tiling,
+    seamless: bool,
 }
 
 #[derive(Deserialize)]
@@ -26,21 +34,12 @@ impl AutoEncoder for Vq {
             return Ok(ndarray::ArrayD::zeros([].as_slice()));
         }
 
-        let session = if let Some(session) = &self.encoder_session {
-            session
+        let pixels = x.shape()[2] * x.shape()[3];
+        if let Some(t) = self.tiling.select(pixels) {
+            tile::execute_tiled(x, &t, LATENT_SCALE, |t| self.encode_tile(t), |_, _| {})
         } else {
-            self.encoder_session
-                .insert(Session::load(&self.path, "encoder.onnx", true)?)
-        };
-        let x = x * 2.0 - 1.0;
-
-        let mut run = session.prepare();
-        run.set_input("img", &x)?;
-        let out = run.exec(true)?;
-        let out = out.get_output_idx::<f32, ndarray::Ix4>(0)?;
-        let ch = out.shape()[1];
-        let mean = out.slice_axis(ndarray::Axis(1), ndarray::Slice::from(0..ch / 2));
-        Ok(mean.to_owned().into_dyn() * self.metadata.scale_factor as f32)
+            self.encode_tile(x)
+        }
     }
 
     fn decode(&mut self, x: &ndarray::ArrayD<f32>) -> Result<ndarray::ArrayD<f32>> {
@@ -48,20 +47,26 @@ impl AutoEncoder for Vq {
             return Ok(ndarray::ArrayD::zeros([].as_slice()));
         }
 
-        let session = if let Some(session) = &self.decoder_session {
-            session
+        if self.seamless {
+            let pad = SEAMLESS_PAD.min(x.shape()[2]).min(x.shape()[3]);
+            let out = self.decode_tiled(&tile::pad_wrap(x, pad))?;
+            let crop = pad * LATENT_SCALE;
+            let (h, w) = (out.shape()[2], out.shape()[3]);
+            Ok(out
+                .slice_axis(Axis(2), Slice::from(crop..h - crop))
+                .slice_axis(Axis(3), Slice::from(crop..w - crop))
+                .to_owned())
         } else {
-            self.decoder_session
-                .insert(Session::load(&self.path, "decoder.onnx", true)?)
-        };
-        let x = (1. / self.metadata.scale_factor) as f32 * x;
+            self.decode_tiled(x)
+        }
+    }
 
-        let mut run = session.prepare();
-        run.set_input("z", &x)?;
-        let out = run.exec(true)?;
-        let out = out.get_output_idx::<f32, ndarray::Ix4>(0)?;
-        let out = ((out.to_owned() + 1.0) / 2.0).mapv(|v| v.clamp(0.0, 1.0));
-        Ok(out.into_dyn().to_owned())
+    fn set_tiling(&mut self, tiling: Tiling) {
+        self.tiling = tiling;
+    }
+
+    fn set_seamless(&mut self, seamless: bool) {
+        self.seamless = seamless;
     }
 }
 
@@ -73,7 +78,7 @@ impl Model for Vq {
 }
 
 impl Vq {
-    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+    pub fn new(path: impl Into<PathBuf>, options: SessionOptions) -> Result<Self> {
         let path = path.into();
         let metadata_json = if path.is_dir() {
             std::fs::read_to_string(path.join("metadata.json"))?
@@ -91,6 +96,86 @@ impl Vq {
             path,
             encoder_session: None,
             decoder_session: None,
+            options,
+            tiling: Tiling::Auto {
+                max_pixels: 1024 * 1024,
+                tile: TileConfig {
+                    tile_size: 512,
+                    overlap: 64,
+                },
+            },
+            seamless: false,
         })
     }
+
+    fn decode_tiled(&mut self, x: &ndarray::ArrayD<f32>) -> Result<ndarray::ArrayD<f32>> {
+        let pixels = x.shape()[2] * x.shape()[3] * LATENT_SCALE * LATENT_SCALE;
+        if let Some(t) = self.tiling.select(pixels) {
+            let t = TileConfig {
+                tile_size: t.tile_size / LATENT_SCALE,
+                overlap: t.overlap / LATENT_SCALE,
+            };
+            tile::execute_tiled(x, &t, 1, |t| self.decode_tile(t), |_, _| {})
+        } else {
+            self.decode_tile(x)
+        }
+    }
+
+    fn encode_tile(&mut self, x: &ndarray::ArrayD<f32>) -> Result<ndarray::ArrayD<f32>> {
+        let session = if let Some(session) = &self.encoder_session {
+            session
+        } else {
+            self.encoder_session.insert(Session::load_with(
+                &self.path,
+                "encoder.onnx",
+                &self.options,
+            )?)
+        };
+        let x = x * 2.0 - 1.0;
+
+        let mut run = session.prepare();
+        run.set_input("img", &x)?;
+        let out = run.exec(true)?;
+        let out = out.get_output_idx::<f32, ndarray::Ix4>(0)?;
+        let ch = out.shape()[1];
+        let mean = out.slice_axis(ndarray::Axis(1), ndarray::Slice::from(0..ch / 2));
+        Ok(mean.to_owned().into_dyn() * self.metadata.scale_factor as f32)
+    }
+
+    fn decode_tile(&mut self, x: &ndarray::ArrayD<f32>) -> Result<ndarray::ArrayD<f32>> {
+        let session = if let Some(session) = &self.decoder_session {
+            session
+        } else {
+            self.decoder_session.insert(Session::load_with(
+                &self.path,
+                "decoder.onnx",
+                &self.options,
+            )?)
+        };
+        let x = (1. / self.metadata.scale_factor) as f32 * x;
+
+        let mut run = session.prepare();
+        run.set_input("z", &x)?;
+        let (name, info) = &session.signature().outputs[0];
+        let shape = [
+            x.shape()[0],
+            match info.shape.get(1) {
+                Some(Dim::Fixed(c)) => *c,
+                _ => 3,
+            },
+            x.shape()[2] * LATENT_SCALE,
+            x.shape()[3] * LATENT_SCALE,
+        ];
+        if !info.matches(&shape) {
+            return Err(Error::InvalidInput(format!(
+                "decoder output {:?} does not match the latent size",
+                info.shape
+            )));
+        }
+        run.alloc_output::<f32>(name, &shape)?;
+        let mut out = run.exec(true)?;
+        let mut out = out.take_output_idx::<f32, ndarray::IxDyn>(0)?.into_array();
+        out.map_inplace(|v| *v = ((*v + 1.0) / 2.0).clamp(0.0, 1.0));
+        Ok(out)
+    }
 }
@@ -0,0 +1,134 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use serde::Deserialize;
+
+use crate::{
+    ort::{initializer_from_f32, initializer_to_f32, DataType, InitializerTransform},
+    result::{Error, Result},
+    safetensors,
+};
+
+// Where a LoRA module is merged, listed in the model metadata by LoRA key
+// prefix, e.g. `lora_unet_mid_block_attentions_0_proj_in`.
+#[derive(Clone, Deserialize)]
+pub struct LoraTarget {
+    pub initializer: String,
+    // MatMul weights are stored as [in, out], transposed from the LoRA layout
+    #[serde(default)]
+    pub transpose: bool,
+}
+
+struct LoraModule {
+    up: ndarray::Array2<f32>,
+    down: ndarray::Array2<f32>,
+    scale: f32,
+    transpose: bool,
+}
+
+// Merges `strength * alpha / rank * up @ down` into the mapped initializers.
+pub struct LoraTransform {
+    modules: HashMap<String, Vec<LoraModule>>,
+}
+
+impl LoraTransform {
+    pub fn new(loras: &[(PathBuf, f32)], keys: &HashMap<String, LoraTarget>) -> Result<Self> {
+        let mut modules = HashMap::<String, Vec<LoraModule>>::new();
+        for (path, strength) in loras {
+            let mut tensors = safetensors::load(path)?;
+            let prefixes: Vec<_> = tensors
+                .keys()
+                .filter_map(|k| k.strip_suffix(".lora_up.weight"))
+                .map(|k| k.to_owned())
+                .collect();
+
+            let mut matched = 0;
+            for prefix in prefixes {
+                let target = match keys.get(&prefix) {
+                    Some(t) => t,
+                    None => continue,
+                };
+                let up = tensors.remove(&format!("{}.lora_up.weight", prefix));
+                let down = tensors.remove(&format!("{}.lora_down.weight", prefix));
+                let (up, down) = match (up, down) {
+                    (Some(up), Some(down)) => (flatten(up)?, flatten(down)?),
+                    _ => continue,
+                };
+                let rank = down.shape()[0];
+                if up.shape()[1] != rank {
+                    return Err(Error::InvalidInput(format!(
+                        "lora rank mismatch for {}",
+                        prefix
+                    )));
+                }
+                let alpha = tensors
+                    .get(&format!("{}.alpha", prefix))
+                    .and_then(|a| a.iter().next().copied())
+                    .unwrap_or(rank as f32);
+
+                modules
+                    .entry(target.initializer.clone())
+                    .or_default()
+                    .push(LoraModule {
+                        up,
+                        down,
+                        scale: strength * alpha / rank as f32,
+                        transpose: target.transpose,
+                    });
+                matched += 1;
+            }
+
+            if matched == 0 {
+                return Err(Error::InvalidInput(format!(
+                    "no lora weights of {} match the model",
+                    path.display()
+                )));
+            }
+        }
+        Ok(Self { modules })
+    }
+}
+
+impl InitializerTransform for LoraTransform {
+    fn applies(&self, name: &str, _shape: &[usize]) -> bool {
+        self.modules.contains_key(name)
+    }
+
+    fn apply(
+        &self,
+        name: &str,
+        elem_type: DataType,
+        _shape: &mut Vec<usize>,
+        data: &mut Vec<u8>,
+    ) -> Result<()> {
+        let mut w = initializer_to_f32(elem_type, data)?;
+        for m in &self.modules[name] {
+            let delta = m.up.dot(&m.down) * m.scale;
+            let delta = if m.transpose {
+                delta.reversed_axes()
+            } else {
+                delta
+            };
+            if delta.len() != w.len() {
+                return Err(Error::InvalidInput(format!(
+                    "lora of shape {:?} does not fit {}",
+                    delta.shape(),
+                    name
+                )));
+            }
+            w.iter_mut().zip(delta.iter()).for_each(|(w, d)| *w += d);
+        }
+        *data = initializer_from_f32(elem_type, &w)?;
+        Ok(())
+    }
+    fn required(&self) -> Vec<&str> {
+        self.modules.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+// Linear weights are [out, in], convolutions [out, in, kh, kw].
+fn flatten(x: ndarray::ArrayD<f32>) -> Result<ndarray::Array2<f32>> {
+    let rows = x.shape().first().copied().unwrap_or(1);
+    let cols = x.len() / rows.max(1);
+    x.into_shape((rows, cols))
+        .map_err(|e| Error::InvalidInput(format!("invalid lora weight: {}", e)))
+}
@@ -0,0 +1,106 @@
+use std::{io::Read, path::PathBuf};
+
+use serde::Deserialize;
+
+use super::{DepthEstimator, Model};
+use crate::{
+    imgproc,
+    ort::{Session, SessionOptions},
+    result::{Error, Result},
+};
+
+pub struct Midas {
+    metadata: Metadata,
+    path: PathBuf,
+    session: Option<Session>,
+    options: SessionOptions,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct Metadata {
+    image_size: usize,
+    mean: [f32; 3],
+    std: [f32; 3],
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Self {
+            image_size: 384,
+            mean: [0.5; 3],
+            std: [0.5; 3],
+        }
+    }
+}
+
+impl DepthEstimator for Midas {
+    fn estimate(&mut self, x: &ndarray::ArrayD<f32>) -> Result<ndarray::ArrayD<f32>> {
+        if x.is_empty() {
+            return Ok(ndarray::ArrayD::zeros([].as_slice()));
+        }
+        if x.ndim() != 4 || x.shape()[1] != 3 {
+            return Err(Error::InvalidInput(format!(
+                "expected RGB image, got shape {:?}",
+                x.shape()
+            )));
+        }
+
+        let session = if let Some(session) = &self.session {
+            session
+        } else {
+            self.session
+                .insert(Session::load_with(&self.path, "depth.onnx", &self.options)?)
+        };
+
+        let (h, w) = (x.shape()[2], x.shape()[3]);
+        let size = self.metadata.image_size;
+        let mut inp = imgproc::resize(x, size, size);
+        imgproc::normalize(&mut inp, &self.metadata.mean, &self.metadata.std);
+
+        let mut run = session.prepare();
+        run.set_input("input", &inp)?;
+        let out = run.exec(true)?;
+        let out = out.get_output_idx::<f32, ndarray::IxDyn>(0)?;
+        let out = match out.ndim() {
+            3 => out.insert_axis(ndarray::Axis(1)),
+            4 => out,
+            n => {
+                return Err(Error::InvalidInput(format!(
+                    "invalid depth output dimension {}",
+                    n
+                )))
+            }
+        };
+        Ok(imgproc::resize(&out.to_owned(), h, w))
+    }
+}
+
+impl Model for Midas {
+    fn unload_model(&mut self) {
+        self.session = None;
+    }
+}
+
+impl Midas {
+    pub fn new(path: impl Into<PathBuf>, options: SessionOptions) -> Result<Self> {
+        let path = path.into();
+        let metadata_json = if path.is_dir() {
+            std::fs::read_to_string(path.join("metadata.json"))?
+        } else {
+            let mut ar = tsar::Archive::new(std::fs::File::open(&path)?)?;
+            let mut buf = String::new();
+            ar.file_by_name("metadata.json")?.read_to_string(&mut buf)?;
+            buf
+        };
+
+        let metadata: Metadata = serde_json::from_str(&metadata_json)?;
+
+        Ok(Self {
+            metadata,
+            path,
+            session: None,
+            options,
+        })
+    }
+}
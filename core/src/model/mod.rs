@@ -1,16 +1,43 @@
+mod aesthetic_predictor;
 mod auto_encoder;
+mod captioner;
+mod control_net;
+mod depth_estimator;
 mod diffusion;
+mod face_detector;
+mod face_restoration;
+mod image_encoder;
+mod kind;
+mod lora;
+mod safety_checker;
+mod scorer;
 mod super_resolution;
 mod text_encoder;
 
+pub use aesthetic_predictor::*;
 pub use auto_encoder::*;
+pub use captioner::*;
+pub use control_net::*;
+pub use depth_estimator::*;
 pub use diffusion::*;
+pub use face_detector::*;
+pub use face_restoration::*;
+pub use image_encoder::*;
+pub use kind::detect_kind;
+pub use lora::*;
+pub use safety_checker::*;
+pub use scorer::*;
 pub use super_resolution::*;
 pub use text_encoder::*;
 
+mod blip;
 mod clip;
+mod codeformer;
 mod esrgan;
+mod gfpgan;
 mod ldm;
+mod midas;
+mod retinaface;
 mod swinir;
 
 pub trait Model: Send + Sync {
@@ -0,0 +1,123 @@
+use std::{io::Read, path::PathBuf};
+
+use ndarray::Axis;
+use serde::Deserialize;
+
+use super::{Face, FaceDetector, Model};
+use crate::{
+    imgproc,
+    ort::{Session, SessionOptions},
+    result::{Error, Result},
+};
+
+// Face detector exported with box decoding and NMS in the graph, returning
+// [N, 15] rows of bbox, score and 5 landmarks in input pixels.
+pub struct RetinaFace {
+    metadata: Metadata,
+    path: PathBuf,
+    session: Option<Session>,
+    options: SessionOptions,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct Metadata {
+    image_size: usize,
+    bgr: bool,
+    mean: [f32; 3],
+    std: [f32; 3],
+    score_threshold: f32,
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Self {
+            image_size: 640,
+            bgr: true,
+            mean: [104. / 255., 117. / 255., 123. / 255.],
+            std: [1. / 255.; 3],
+            score_threshold: 0.5,
+        }
+    }
+}
+
+impl FaceDetector for RetinaFace {
+    fn detect(&mut self, x: &ndarray::ArrayD<f32>) -> Result<Vec<Face>> {
+        if x.is_empty() {
+            return Ok(vec![]);
+        }
+        if x.ndim() != 4 || x.shape()[0] != 1 || x.shape()[1] != 3 {
+            return Err(Error::InvalidInput(format!(
+                "expected a single RGB image, got shape {:?}",
+                x.shape()
+            )));
+        }
+
+        let session = if let Some(session) = &self.session {
+            session
+        } else {
+            self.session.insert(Session::load_with(
+                &self.path,
+                "detector.onnx",
+                &self.options,
+            )?)
+        };
+
+        let (h, w) = (x.shape()[2], x.shape()[3]);
+        let size = self.metadata.image_size;
+        let mut inp = imgproc::resize(x, size, size);
+        if self.metadata.bgr {
+            inp.invert_axis(Axis(1));
+            inp = inp.as_standard_layout().to_owned();
+        }
+        imgproc::normalize(&mut inp, &self.metadata.mean, &self.metadata.std);
+
+        let mut run = session.prepare();
+        run.set_input("input", &inp)?;
+        let out = run.exec(true)?;
+        let out = out.get_output_idx::<f32, ndarray::IxDyn>(0)?;
+        let out = out.to_shape((out.len() / 15, 15)).map_err(|_| {
+            Error::InvalidInput(format!("invalid detector output {:?}", out.shape()))
+        })?;
+
+        let (sx, sy) = (w as f32 / size as f32, h as f32 / size as f32);
+        Ok(out
+            .axis_iter(Axis(0))
+            .filter(|r| r[4] >= self.metadata.score_threshold)
+            .map(|r| Face {
+                bbox: [r[0] * sx, r[1] * sy, r[2] * sx, r[3] * sy],
+                score: r[4],
+                landmarks: std::array::from_fn(|i| [r[5 + i * 2] * sx, r[6 + i * 2] * sy]),
+            })
+            .collect())
+    }
+}
+
+impl Model for RetinaFace {
+    fn unload_model(&mut self) {
+        self.session = None;
+    }
+}
+
+impl RetinaFace {
+    pub fn new(path: impl Into<PathBuf>, options: SessionOptions) -> Result<Self> {
+        let path = path.into();
+        let metadata_json = if path.is_dir() {
+            std::fs::read_to_string(path.join("metadata.json"))?
+        } else {
+            let mut ar = tsar::Archive::new(std::fs::File::open(&path)?)?;
+            let mut buf = String::new();
+            ar.file_by_name("metadata.json")?.read_to_string(&mut buf)?;
+            buf
+        };
+
+        let metadata: Metadata = serde_json::from_str(&metadata_json)?;
+
+        Ok(Self {
+            metadata,
+            path,
+            session: None,
+            options,
+        })
+    }
+}
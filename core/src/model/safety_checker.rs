@@ -0,0 +1,74 @@
+use std::{path::Path, str::FromStr};
+
+use ndarray::Axis;
+
+use super::{clip, kind::resolve_kind, Model};
+use crate::{
+    imgproc,
+    ort::SessionOptions,
+    result::{Error, Result},
+};
+
+pub trait SafetyChecker: Model {
+    // Returns whether each image in the batch is flagged as unsafe.
+    fn check(&mut self, x: &ndarray::ArrayD<f32>) -> Result<Vec<bool>>;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SafetyAction {
+    Blur,
+    BlackOut,
+    // only record the result, leaving the image untouched
+    #[default]
+    Report,
+}
+
+impl SafetyAction {
+    pub fn apply(&self, x: &mut ndarray::ArrayD<f32>, flagged: &[bool]) {
+        for (mut img, _) in x.axis_iter_mut(Axis(0)).zip(flagged).filter(|(_, f)| **f) {
+            match self {
+                SafetyAction::Blur => {
+                    let mut b = img.to_owned().insert_axis(Axis(0));
+                    let radius = (b.shape()[2].max(b.shape()[3]) / 32).max(1);
+                    for _ in 0..3 {
+                        b = imgproc::box_blur(&b, radius);
+                    }
+                    img.assign(&b.index_axis(Axis(0), 0));
+                }
+                SafetyAction::BlackOut => img.fill(0.),
+                SafetyAction::Report => {}
+            }
+        }
+    }
+}
+
+impl FromStr for SafetyAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "blur" => Ok(SafetyAction::Blur),
+            "black-out" => Ok(SafetyAction::BlackOut),
+            "report" => Ok(SafetyAction::Report),
+            s => Err(Error::InvalidInput(format!("unknown safety action: {}", s))),
+        }
+    }
+}
+
+pub fn load_safety_checker(
+    kind: impl AsRef<str>,
+    path: impl AsRef<Path>,
+    options: &SessionOptions,
+) -> Result<Box<dyn SafetyChecker>> {
+    match resolve_kind(kind.as_ref(), path.as_ref())?.as_str() {
+        "clip/safety-checker" => Ok(Box::new(clip::ClipSafetyChecker::new(
+            path.as_ref(),
+            options.clone(),
+        )?)),
+
+        k => Err(Error::UnsupportedModel(
+            "safety checker".to_string(),
+            k.to_owned(),
+        )),
+    }
+}
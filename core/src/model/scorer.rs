@@ -0,0 +1,110 @@
+use std::cmp::Ordering;
+
+use ndarray::Axis;
+
+use super::{AestheticPredictor, ImageEncoder, Model, TextEncoder};
+use crate::result::{Error, Result};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Score {
+    pub similarity: f32,
+    pub aesthetic: Option<f32>,
+}
+
+// Scores images by CLIP similarity to a prompt and, optionally, by predicted aesthetics.
+pub struct ClipScorer {
+    image_encoder: Box<dyn ImageEncoder>,
+    text_encoder: Box<dyn TextEncoder>,
+    aesthetic: Option<Box<dyn AestheticPredictor>>,
+    pub aesthetic_weight: f32,
+}
+
+impl ClipScorer {
+    pub fn new(image_encoder: Box<dyn ImageEncoder>, text_encoder: Box<dyn TextEncoder>) -> Self {
+        Self {
+            image_encoder,
+            text_encoder,
+            aesthetic: None,
+            aesthetic_weight: 0.02,
+        }
+    }
+
+    pub fn with_aesthetic(mut self, aesthetic: Box<dyn AestheticPredictor>) -> Self {
+        self.aesthetic = Some(aesthetic);
+        self
+    }
+
+    pub fn score(&mut self, images: &ndarray::ArrayD<f32>, prompt: &str) -> Result<Vec<Score>> {
+        if images.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let enc = self.text_encoder.tokenize(prompt)?;
+        let text = flatten(self.text_encoder.encode(&[enc])?)?;
+        let image = flatten(self.image_encoder.encode(images)?)?;
+        if text.shape()[1] != image.shape()[1] {
+            return Err(Error::InvalidInput(format!(
+                "text embedding size {} does not match image embedding size {}",
+                text.shape()[1],
+                image.shape()[1]
+            )));
+        }
+
+        let aesthetic = match &mut self.aesthetic {
+            Some(m) => Some(m.predict(&image.clone().into_dyn())?),
+            None => None,
+        };
+
+        let text = text.index_axis(Axis(0), 0);
+        Ok(image
+            .axis_iter(Axis(0))
+            .enumerate()
+            .map(|(i, emb)| Score {
+                similarity: cosine_similarity(emb, text),
+                aesthetic: aesthetic.as_ref().map(|a| a[i]),
+            })
+            .collect())
+    }
+
+    pub fn rank(&self, score: &Score) -> f32 {
+        score.similarity + score.aesthetic.unwrap_or(0.) * self.aesthetic_weight
+    }
+
+    // Returns the indices of the `k` best scores, best first.
+    pub fn top_k(&self, scores: &[Score], k: usize) -> Vec<usize> {
+        let mut idx: Vec<_> = (0..scores.len()).collect();
+        idx.sort_by(|&a, &b| {
+            self.rank(&scores[b])
+                .partial_cmp(&self.rank(&scores[a]))
+                .unwrap_or(Ordering::Equal)
+        });
+        idx.truncate(k);
+        idx
+    }
+}
+
+impl Model for ClipScorer {
+    fn unload_model(&mut self) {
+        self.image_encoder.unload_model();
+        self.text_encoder.unload_model();
+        if let Some(m) = &mut self.aesthetic {
+            m.unload_model();
+        }
+    }
+}
+
+pub fn cosine_similarity(a: ndarray::ArrayView1<f32>, b: ndarray::ArrayView1<f32>) -> f32 {
+    let norm = (a.dot(&a) * b.dot(&b)).sqrt();
+    if norm > 0. {
+        a.dot(&b) / norm
+    } else {
+        0.
+    }
+}
+
+fn flatten(x: ndarray::ArrayD<f32>) -> Result<ndarray::Array2<f32>> {
+    let b = x.shape().first().copied().unwrap_or(0);
+    let d = x.len() / b.max(1);
+    x.into_shape((b, d))
+        .map_err(|e| Error::InvalidInput(format!("invalid embedding: {}", e)))
+}
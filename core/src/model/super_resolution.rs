@@ -1,19 +1,50 @@
-use std::path::Path;
+use std::{collections::HashMap, path::Path};
 
-use super::{esrgan::Esrgan, swinir::SwinIR, Model};
-use crate::result::{Error, Result};
+use super::{
+    esrgan::Esrgan, kind::resolve_kind, ldm::upscaler::DiffusionUpscaler, swinir::SwinIR, Model,
+};
+use crate::{
+    ort::SessionOptions,
+    result::{Error, Result},
+    tile::{self, TileConfig},
+};
 
 pub trait SuperResolution: Model {
     fn execute(&mut self, x: &ndarray::ArrayD<f32>) -> Result<ndarray::ArrayD<f32>>;
+
+    fn execute_tiled(
+        &mut self,
+        x: &ndarray::ArrayD<f32>,
+        tile: &TileConfig,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<ndarray::ArrayD<f32>> {
+        if x.is_empty() {
+            return Ok(ndarray::ArrayD::zeros([].as_slice()));
+        }
+        tile::execute_tiled(x, tile, 1, |t| self.execute(t), progress)
+    }
+
+    // Prompt embeddings for models that are guided by text; others ignore them.
+    fn set_text_condition(
+        &mut self,
+        _condition: &HashMap<String, ndarray::ArrayD<f32>>,
+        _uncondition: &HashMap<String, ndarray::ArrayD<f32>>,
+    ) {
+    }
 }
 
 pub fn load_super_resolution(
     kind: impl AsRef<str>,
     path: impl AsRef<Path>,
+    options: &SessionOptions,
 ) -> Result<Box<dyn SuperResolution>> {
-    match kind.as_ref() {
-        "swinir" => Ok(Box::new(SwinIR::new(path.as_ref())?)),
-        "esrgan" => Ok(Box::new(Esrgan::new(path.as_ref())?)),
+    match resolve_kind(kind.as_ref(), path.as_ref())?.as_str() {
+        "swinir" => Ok(Box::new(SwinIR::new(path.as_ref(), options.clone())?)),
+        "esrgan" => Ok(Box::new(Esrgan::new(path.as_ref(), options.clone())?)),
+        "ldm/upscaler" => Ok(Box::new(DiffusionUpscaler::new(
+            path.as_ref(),
+            options.clone(),
+        )?)),
 
         k => Err(Error::UnsupportedModel(
             "text encoder".to_string(),
@@ -1,11 +1,15 @@
 use std::path::PathBuf;
 
 use super::{Model, SuperResolution};
-use crate::{ort::Session, result::Result};
+use crate::{
+    ort::{Session, SessionOptions},
+    result::Result,
+};
 
 pub struct SwinIR {
     path: PathBuf,
     session: Option<Session>,
+    options: SessionOptions,
 }
 
 impl SuperResolution for SwinIR {
@@ -18,7 +22,7 @@ impl SuperResolution for SwinIR {
             session
         } else {
             self.session
-                .insert(Session::load(&self.path, "sr.onnx", true)?)
+                .insert(Session::load_with(&self.path, "sr.onnx", &self.options)?)
         };
 
         let mut run = session.prepare();
@@ -33,10 +37,11 @@ impl SuperResolution for SwinIR {
 impl Model for SwinIR {}
 
 impl SwinIR {
-    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+    pub fn new(path: impl Into<PathBuf>, options: SessionOptions) -> Result<Self> {
         Ok(Self {
             path: path.into(),
             session: None,
+            options,
         })
     }
 }
@@ -1,21 +1,42 @@
 use std::path::Path;
 
-use super::{clip, ldm::bert, Model};
-use crate::result::{Error, Result};
+use super::{clip, kind::resolve_kind, ldm::bert, Model};
+use crate::{
+    ort::SessionOptions,
+    result::{Error, Result},
+};
 
 pub trait TextEncoder: Model {
     fn tokenize(&mut self, inp: &str) -> Result<tokenizers::Encoding>;
     fn encode(&mut self, enc: &[tokenizers::Encoding]) -> Result<ndarray::ArrayD<f32>>;
+
+    // Registers the textual inversion embeddings of a safetensors file and
+    // returns their placeholder tokens.
+    fn add_embeddings(&mut self, _path: &Path) -> Result<Vec<String>> {
+        Err(Error::Unsupported("textual inversion".to_string()))
+    }
 }
 
 pub fn load_text_encoder(
     kind: impl AsRef<str>,
     path: impl AsRef<Path>,
+    options: &SessionOptions,
 ) -> Result<Box<dyn TextEncoder>> {
-    match kind.as_ref() {
-        "ldm/bert" => Ok(Box::new(bert::BertEncoder::new(path.as_ref())?)),
-        "clip-pos" => Ok(Box::new(clip::ClipEncoder::new(path.as_ref(), 1)?)),
-        "clip" => Ok(Box::new(clip::ClipEncoder::new(path.as_ref(), 0)?)),
+    match resolve_kind(kind.as_ref(), path.as_ref())?.as_str() {
+        "ldm/bert" => Ok(Box::new(bert::BertEncoder::new(
+            path.as_ref(),
+            options.clone(),
+        )?)),
+        "clip-pos" => Ok(Box::new(clip::ClipEncoder::new(
+            path.as_ref(),
+            1,
+            options.clone(),
+        )?)),
+        "clip" => Ok(Box::new(clip::ClipEncoder::new(
+            path.as_ref(),
+            0,
+            options.clone(),
+        )?)),
         k => Err(Error::UnsupportedModel(
             "text encoder".to_string(),
             k.to_owned(),
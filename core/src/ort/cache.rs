@@ -0,0 +1,244 @@
+use std::{
+    ffi::CString,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use ort_sys as sys;
+
+use super::ort_call;
+use crate::{
+    ort::{
+        env::{get_env, version},
+        get_api,
+        session::path_to_cstring,
+        SessionOptions,
+    },
+    result::Result,
+};
+
+const CACHE_DIR: &str = ".ort-cache";
+
+// Graph optimized by ORT, stored next to the model it was made from. The key
+// covers the ORT version, the session options (providers included) and the
+// model: models from the model manager live in a directory named by their
+// manifest sha256, which is used as is, and other models are fingerprinted by
+// the size and modification time of their files.
+pub(super) struct OptimizedModelCache {
+    dir: PathBuf,
+    prefix: String,
+    key: u64,
+}
+
+impl OptimizedModelCache {
+    pub(super) fn new(base: &Path, path: &str, options: &SessionOptions) -> Result<Self> {
+        let stem = Path::new(path)
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let mut hasher = StableHasher::default();
+        hasher.write_str(&version());
+        hasher.write_str(&format!("{:?}", options.without_profiling()));
+        hasher.write_str(path);
+
+        let (dir, prefix) = if base.is_file() {
+            match manifest_sha256(base.parent().unwrap_or(base)) {
+                Some(sha256) => hasher.write_str(sha256),
+                None => fingerprint(base, &mut hasher)?,
+            }
+            let archive = base
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            (
+                base.parent().unwrap_or(Path::new(".")).join(CACHE_DIR),
+                format!("{}.{}", archive, stem),
+            )
+        } else {
+            if let Some(sha256) = manifest_sha256(base) {
+                hasher.write_str(sha256);
+            } else {
+                // the graph and the external data files it references
+                let onnx_dir = base.join(path).parent().unwrap_or(base).to_owned();
+                let mut files = std::fs::read_dir(&onnx_dir)?
+                    .map(|e| Ok(e?.path()))
+                    .collect::<Result<Vec<_>>>()?;
+                files.retain(|p| p.is_file());
+                files.sort();
+                for f in files {
+                    hasher.write_str(&f.file_name().unwrap_or_default().to_string_lossy());
+                    fingerprint(&f, &mut hasher)?;
+                }
+            }
+            (base.join(CACHE_DIR), stem)
+        };
+
+        Ok(Self {
+            dir,
+            prefix,
+            key: hasher.finish(),
+        })
+    }
+
+    fn name(&self) -> String {
+        format!("{}.{:016x}", self.prefix, self.key)
+    }
+
+    fn model_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.onnx", self.name()))
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.onnx.tmp", self.name()))
+    }
+
+    fn data_name(&self) -> String {
+        format!("{}.data", self.name())
+    }
+
+    // Creates the session from the cache, or returns None when there is no
+    // usable cache. A cache that fails to load is removed.
+    pub(super) fn load(
+        &self,
+        session_options: *mut sys::OrtSessionOptions,
+        options: &SessionOptions,
+    ) -> Result<Option<*mut sys::OrtSession>> {
+        let model_path = self.model_path();
+        if !model_path.is_file() {
+            return Ok(None);
+        }
+
+        let api = get_api();
+        // the graph is already optimized
+        ort_call!(
+            api.SetSessionGraphOptimizationLevel,
+            session_options,
+            sys::GraphOptimizationLevel_ORT_DISABLE_ALL,
+        )?;
+        let mut session: *mut sys::OrtSession = std::ptr::null_mut();
+        let onnx = path_to_cstring(&model_path);
+        match ort_call!(
+            api.CreateSession,
+            get_env(),
+            onnx.as_ptr(),
+            session_options,
+            &mut session,
+        ) {
+            Ok(()) => Ok(Some(session)),
+            // a cache ORT can't load is rebuilt like a missing one
+            Err(_) => {
+                let _ = std::fs::remove_file(&model_path);
+                let _ = std::fs::remove_file(self.dir.join(self.data_name()));
+                options.apply_optimization_level(session_options)?;
+                Ok(None)
+            }
+        }
+    }
+
+    // Has ORT write the optimized graph while creating the session; `commit`
+    // makes it visible once the session loaded.
+    pub(super) fn prepare(&self, session_options: *mut sys::OrtSessionOptions) -> Result<bool> {
+        if std::fs::create_dir_all(&self.dir).is_err() {
+            return Ok(false);
+        }
+
+        let api = get_api();
+        let temp = path_to_cstring(self.temp_path());
+        ort_call!(
+            api.SetOptimizedModelFilePath,
+            session_options,
+            temp.as_ptr()
+        )?;
+        let data_name = CString::new(self.data_name()).unwrap();
+        ort_call!(
+            api.AddSessionConfigEntry,
+            session_options,
+            "session.optimized_model_external_initializers_file_name\0".as_ptr() as *const i8,
+            data_name.as_ptr(),
+        )?;
+        ort_call!(
+            api.AddSessionConfigEntry,
+            session_options,
+            "session.optimized_model_external_initializers_min_size_in_bytes\0".as_ptr()
+                as *const i8,
+            "1024\0".as_ptr() as *const i8,
+        )?;
+        Ok(true)
+    }
+
+    // Removes what ORT wrote when the session failed to load.
+    pub(super) fn discard(&self) {
+        let _ = std::fs::remove_file(self.temp_path());
+        let _ = std::fs::remove_file(self.dir.join(self.data_name()));
+    }
+
+    // Publishes the graph written by ORT and removes caches of other keys.
+    pub(super) fn commit(&self) -> Result<()> {
+        std::fs::rename(self.temp_path(), self.model_path())?;
+
+        let current = self.name();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            let stale = name
+                .strip_prefix(&self.prefix)
+                .and_then(|n| n.strip_prefix('.'))
+                .map_or(false, |n| {
+                    // keys are fixed width, so other models with this prefix don't match
+                    n.len() > 16
+                        && n.as_bytes()[16] == b'.'
+                        && n[..16].bytes().all(|b| b.is_ascii_hexdigit())
+                });
+            if stale && !name.starts_with(&current) {
+                let _ = std::fs::remove_file(self.dir.join(name));
+            }
+        }
+        Ok(())
+    }
+}
+
+// The sha256 a model directory from the model manager is named by.
+fn manifest_sha256(dir: &Path) -> Option<&str> {
+    let name = dir.file_name()?.to_str()?;
+    (name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())).then_some(name)
+}
+
+fn fingerprint(path: &Path, hasher: &mut StableHasher) -> Result<()> {
+    let meta = std::fs::metadata(path)?;
+    hasher.write(&meta.len().to_le_bytes());
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    hasher.write(&modified.as_nanos().to_le_bytes());
+    Ok(())
+}
+
+// FNV-1a, unlike `DefaultHasher` the same across Rust releases, so caches
+// outlive toolchain updates. Fields are written as bytes, not through `Hash`.
+struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ b as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    // length prefixed, so adjacent strings can't run into each other
+    fn write_str(&mut self, s: &str) {
+        self.write(&(s.len() as u64).to_le_bytes());
+        self.write(s.as_bytes());
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
@@ -0,0 +1,86 @@
+use std::{borrow::Cow, collections::HashMap, io::Read, path::Path};
+
+use memmap2::{MmapMut, MmapOptions};
+
+use crate::result::Result;
+
+// A tsar archive mapped into memory. tsar keeps every blob in the zip entry
+// of the same name, and blobs it stores as is, without compression or any
+// other encoding, can be handed to ORT straight from the map instead of being
+// read into memory first.
+pub(super) struct MappedArchive {
+    // copy on write, in case ORT ever writes to an initializer
+    map: MmapMut,
+    // offset and size of the uncompressed zip entries by name
+    stored: HashMap<String, (u64, u64)>,
+}
+
+impl MappedArchive {
+    // None if the archive can't be mapped, in which case blobs are read as before.
+    pub(super) fn open(path: &Path) -> Option<Self> {
+        let file = std::fs::File::open(path).ok()?;
+        let map = unsafe { MmapOptions::new().map_copy(&file) }.ok()?;
+
+        let mut zip = zip::ZipArchive::new(file).ok()?;
+        let mut stored = HashMap::new();
+        for i in 0..zip.len() {
+            let f = zip.by_index_raw(i).ok()?;
+            if f.compression() == zip::CompressionMethod::Stored {
+                stored.insert(f.name().to_owned(), (f.data_start(), f.size()));
+            }
+        }
+        Some(Self { map, stored })
+    }
+
+    // Reads the blob `name` of `len` bytes, borrowing it from the map when tsar
+    // reports an `identity` encoding and its entry is stored uncompressed at an
+    // offset aligned to `align`. Other blobs are decoded by `blob`.
+    pub(super) fn read(
+        &self,
+        name: &str,
+        blob: &mut impl Read,
+        len: usize,
+        align: usize,
+        identity: bool,
+    ) -> Result<Cow<'_, [u8]>> {
+        if let Some(&(offset, size)) = self.stored.get(name).filter(|_| identity) {
+            let offset = offset as usize;
+            if size as usize == len && offset % align.max(1) == 0 {
+                if let Some(data) = self.map.get(offset..offset + len) {
+                    return Ok(Cow::Borrowed(data));
+                }
+            }
+        }
+        let mut data = Vec::with_capacity(len);
+        blob.read_to_end(&mut data)?;
+        Ok(Cow::Owned(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn test_read() {
+        let path = std::env::temp_dir().join(format!("artspace-mapped-{}.zip", std::process::id()));
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        zip.start_file("w", options).unwrap();
+        zip.write_all(&[1, 2, 3, 4]).unwrap();
+        zip.finish().unwrap();
+
+        let m = MappedArchive::open(&path).unwrap();
+        let decoded = [5u8, 6, 7, 8];
+        let r = m.read("w", &mut &decoded[..], 4, 1, true).unwrap();
+        assert!(matches!(r, Cow::Borrowed(&[1, 2, 3, 4])));
+        // a stored entry with another encoding holds bytes that aren't the tensor
+        let r = m.read("w", &mut &decoded[..], 4, 1, false).unwrap();
+        assert!(matches!(r, Cow::Owned(ref v) if v == &decoded));
+        drop(m);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    io::Read,
+    path::Path,
+};
+
+use ort_sys as sys;
+
+use super::{onnx, ort_call};
+use crate::{ort::get_api, result::Result};
+
+// Metadata stored in the ONNX graph by the exporter.
+#[derive(Clone, Debug, Default)]
+pub struct ModelMetadata {
+    pub producer: String,
+    pub graph_name: String,
+    pub domain: String,
+    pub description: String,
+    pub version: i64,
+    pub custom: HashMap<String, String>,
+}
+
+// Metadata and input names of the graph `path` of the model at `base`, read
+// from the ONNX file without creating a session.
+pub(crate) fn read_graph_metadata(base: &Path, path: &str) -> Result<(ModelMetadata, Vec<String>)> {
+    let data = if base.is_dir() {
+        std::fs::read(base.join(path))?
+    } else {
+        let mut data = Vec::new();
+        tsar::Archive::new(std::fs::File::open(base)?)?
+            .file_by_name(path)?
+            .read_to_end(&mut data)?;
+        data
+    };
+    onnx::model_metadata(&data)
+}
+
+pub(super) fn read_metadata(session: *mut sys::OrtSession) -> Result<ModelMetadata> {
+    let api = get_api();
+    let mut alloc: *mut sys::OrtAllocator = std::ptr::null_mut();
+    ort_call!(api.GetAllocatorWithDefaultOptions, &mut alloc)?;
+    // copies and frees a string allocated by ORT
+    let take = |s: *mut i8| {
+        let r = unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned();
+        unsafe {
+            (*alloc).Free.unwrap()(alloc, s as *mut _);
+        }
+        r
+    };
+
+    let mut meta: *mut sys::OrtModelMetadata = std::ptr::null_mut();
+    ort_call!(api.SessionGetModelMetadata, session, &mut meta)?;
+    defer! {
+        unsafe { api.ReleaseModelMetadata.unwrap()(meta); }
+    }
+
+    let mut result = ModelMetadata::default();
+    for (f, dst) in [
+        (api.ModelMetadataGetProducerName, &mut result.producer),
+        (api.ModelMetadataGetGraphName, &mut result.graph_name),
+        (api.ModelMetadataGetDomain, &mut result.domain),
+        (api.ModelMetadataGetDescription, &mut result.description),
+    ] {
+        let mut s: *mut i8 = std::ptr::null_mut();
+        ort_call!(f, meta, alloc, &mut s)?;
+        *dst = take(s);
+    }
+    ort_call!(api.ModelMetadataGetVersion, meta, &mut result.version)?;
+
+    let mut keys: *mut *mut i8 = std::ptr::null_mut();
+    let mut num_keys: i64 = 0;
+    ort_call!(
+        api.ModelMetadataGetCustomMetadataMapKeys,
+        meta,
+        alloc,
+        &mut keys,
+        &mut num_keys,
+    )?;
+    if keys.is_null() {
+        return Ok(result);
+    }
+    let names: Vec<String> = (0..num_keys as usize)
+        .map(|i| take(unsafe { *keys.add(i) }))
+        .collect();
+    unsafe {
+        (*alloc).Free.unwrap()(alloc, keys as *mut _);
+    }
+    for k in names {
+        let ck = CString::new(k.as_str()).expect("CString::new failed");
+        let mut v: *mut i8 = std::ptr::null_mut();
+        ort_call!(
+            api.ModelMetadataLookupCustomMetadataMap,
+            meta,
+            alloc,
+            ck.as_ptr(),
+            &mut v,
+        )?;
+        if !v.is_null() {
+            result.custom.insert(k, take(v));
+        }
+    }
+    Ok(result)
+}
@@ -2,11 +2,27 @@ use std::sync::atomic::{AtomicPtr, Ordering};
 
 use ort_sys as sys;
 
+mod cache;
 mod cuda;
 mod env;
+mod mapped;
+mod metadata;
+mod onnx;
+mod options;
+mod profile;
+mod residency;
 mod session;
+mod tensor;
+mod transform;
 pub use env::{deinit, list_providers, version};
-pub use session::{DataType, Session, TensorInfo};
+pub(crate) use metadata::read_graph_metadata;
+pub use metadata::ModelMetadata;
+pub use options::{ExecutionProvider, GraphOptimizationLevel, SessionOptions};
+pub use profile::{take_profiles, NodeTiming, SessionProfile};
+pub use residency::{memory_budget, resident_size, set_memory_budget};
+pub use session::{DataType, Dim, IoBinding, Session, Signature, TensorInfo};
+pub use tensor::Tensor;
+pub use transform::{initializer_from_f32, initializer_to_f32, InitializerTransform};
 
 pub struct Error(sys::OrtErrorCode, String);
 
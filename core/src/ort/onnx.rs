@@ -0,0 +1,295 @@
+// Minimal protobuf reader for the external initializers and the metadata of an
+// ONNX model, only the fields of ModelProto, GraphProto and TensorProto needed
+// for those are decoded.
+
+use ort_sys as sys;
+
+use super::ModelMetadata;
+use crate::result::{Error, Result};
+
+const MODEL_PRODUCER_NAME: u32 = 2;
+const MODEL_DOMAIN: u32 = 4;
+const MODEL_VERSION: u32 = 5;
+const MODEL_DOC_STRING: u32 = 6;
+const MODEL_GRAPH: u32 = 7;
+const MODEL_METADATA_PROPS: u32 = 14;
+const GRAPH_NAME: u32 = 2;
+const GRAPH_INITIALIZER: u32 = 5;
+const GRAPH_INPUT: u32 = 11;
+const VALUE_INFO_NAME: u32 = 1;
+const TENSOR_DIMS: u32 = 1;
+const TENSOR_DATA_TYPE: u32 = 2;
+const TENSOR_NAME: u32 = 8;
+const TENSOR_EXTERNAL_DATA: u32 = 13;
+const TENSOR_DATA_LOCATION: u32 = 14;
+const DATA_LOCATION_EXTERNAL: u64 = 1;
+
+pub struct ExternalInitializer {
+    pub name: String,
+    pub dims: Vec<usize>,
+    pub elem_type: sys::ONNXTensorElementDataType,
+    pub location: String,
+    pub offset: u64,
+    pub length: Option<u64>,
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn varint(&mut self) -> Result<u64> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&b, rest) = self.buf.split_first().ok_or_else(truncated)?;
+            self.buf = rest;
+            v |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(Error::InvalidInput("invalid protobuf varint".to_string()))
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if n > self.buf.len() {
+            return Err(truncated());
+        }
+        let (v, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(v)
+    }
+
+    fn next_field(&mut self) -> Result<Option<(u32, Value<'a>)>> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let v = match key & 7 {
+            0 => Value::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Value::Fixed
+            }
+            2 => {
+                let n = self.varint()? as usize;
+                Value::Bytes(self.take(n)?)
+            }
+            5 => {
+                self.take(4)?;
+                Value::Fixed
+            }
+            t => {
+                return Err(Error::InvalidInput(format!(
+                    "unsupported protobuf wire type {}",
+                    t
+                )))
+            }
+        };
+        Ok(Some(((key >> 3) as u32, v)))
+    }
+}
+
+fn truncated() -> Error {
+    Error::InvalidInput("truncated protobuf message".to_string())
+}
+
+fn fields(buf: &[u8], mut f: impl FnMut(u32, Value) -> Result<()>) -> Result<()> {
+    let mut r = Reader { buf };
+    while let Some((k, v)) = r.next_field()? {
+        f(k, v)?;
+    }
+    Ok(())
+}
+
+fn string(v: &[u8]) -> Result<String> {
+    String::from_utf8(v.to_vec())
+        .map_err(|_| Error::InvalidInput("invalid protobuf string".to_string()))
+}
+
+fn parse_tensor(buf: &[u8]) -> Result<Option<ExternalInitializer>> {
+    let mut t = ExternalInitializer {
+        name: String::new(),
+        dims: Vec::new(),
+        elem_type: 0,
+        location: String::new(),
+        offset: 0,
+        length: None,
+    };
+    let mut external = false;
+    fields(buf, |k, v| {
+        match (k, v) {
+            (TENSOR_DIMS, Value::Varint(d)) => t.dims.push(d as usize),
+            (TENSOR_DIMS, Value::Bytes(packed)) => {
+                let mut r = Reader { buf: packed };
+                while !r.buf.is_empty() {
+                    t.dims.push(r.varint()? as usize);
+                }
+            }
+            (TENSOR_DATA_TYPE, Value::Varint(d)) => {
+                t.elem_type = d as sys::ONNXTensorElementDataType
+            }
+            (TENSOR_NAME, Value::Bytes(s)) => t.name = string(s)?,
+            (TENSOR_DATA_LOCATION, Value::Varint(d)) => external = d == DATA_LOCATION_EXTERNAL,
+            (TENSOR_EXTERNAL_DATA, Value::Bytes(entry)) => {
+                let (mut key, mut value) = (String::new(), String::new());
+                fields(entry, |k, v| {
+                    match (k, v) {
+                        (1, Value::Bytes(s)) => key = string(s)?,
+                        (2, Value::Bytes(s)) => value = string(s)?,
+                        _ => {}
+                    }
+                    Ok(())
+                })?;
+                let int = || {
+                    value
+                        .parse()
+                        .map_err(|_| Error::InvalidInput(format!("invalid {}: {}", key, value)))
+                };
+                match key.as_str() {
+                    "location" => t.location = value.clone(),
+                    "offset" => t.offset = int()?,
+                    "length" => t.length = Some(int()?),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(if external { Some(t) } else { None })
+}
+
+// Lists the initializers of a serialized ModelProto whose data is stored in external files.
+pub fn external_initializers(model: &[u8]) -> Result<Vec<ExternalInitializer>> {
+    let mut result = Vec::new();
+    fields(model, |k, v| {
+        if let (MODEL_GRAPH, Value::Bytes(graph)) = (k, v) {
+            fields(graph, |k, v| {
+                if let (GRAPH_INITIALIZER, Value::Bytes(tensor)) = (k, v) {
+                    result.extend(parse_tensor(tensor)?);
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    })?;
+    Ok(result)
+}
+
+// Reads the metadata ORT would report for a serialized ModelProto, along with
+// the names of the graph inputs, without loading the model.
+pub fn model_metadata(model: &[u8]) -> Result<(ModelMetadata, Vec<String>)> {
+    let mut meta = ModelMetadata::default();
+    let mut inputs = Vec::new();
+    fields(model, |k, v| {
+        match (k, v) {
+            (MODEL_PRODUCER_NAME, Value::Bytes(s)) => meta.producer = string(s)?,
+            (MODEL_DOMAIN, Value::Bytes(s)) => meta.domain = string(s)?,
+            (MODEL_VERSION, Value::Varint(d)) => meta.version = d as i64,
+            (MODEL_DOC_STRING, Value::Bytes(s)) => meta.description = string(s)?,
+            (MODEL_METADATA_PROPS, Value::Bytes(entry)) => {
+                let (mut key, mut value) = (String::new(), String::new());
+                fields(entry, |k, v| {
+                    match (k, v) {
+                        (1, Value::Bytes(s)) => key = string(s)?,
+                        (2, Value::Bytes(s)) => value = string(s)?,
+                        _ => {}
+                    }
+                    Ok(())
+                })?;
+                meta.custom.insert(key, value);
+            }
+            (MODEL_GRAPH, Value::Bytes(graph)) => fields(graph, |k, v| {
+                match (k, v) {
+                    (GRAPH_NAME, Value::Bytes(s)) => meta.graph_name = string(s)?,
+                    (GRAPH_INPUT, Value::Bytes(info)) => fields(info, |k, v| {
+                        if let (VALUE_INFO_NAME, Value::Bytes(s)) = (k, v) {
+                            inputs.push(string(s)?);
+                        }
+                        Ok(())
+                    })?,
+                    _ => {}
+                }
+                Ok(())
+            })?,
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok((meta, inputs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(k: u32, v: &[u8]) -> Vec<u8> {
+        let mut out = vec![((k << 3) | 2) as u8, v.len() as u8];
+        out.extend_from_slice(v);
+        out
+    }
+
+    #[test]
+    fn test_external_initializers() {
+        let mut tensor = vec![
+            (TENSOR_DIMS << 3) as u8,
+            3,
+            (TENSOR_DIMS << 3) as u8,
+            0x80,
+            0x01,
+        ];
+        tensor.extend([(TENSOR_DATA_TYPE << 3) as u8, 1]);
+        tensor.extend(field(TENSOR_NAME, b"w"));
+        for (k, v) in [("location", "w.bin"), ("offset", "256")] {
+            let entry = [field(1, k.as_bytes()), field(2, v.as_bytes())].concat();
+            tensor.extend(field(TENSOR_EXTERNAL_DATA, &entry));
+        }
+        tensor.extend([(TENSOR_DATA_LOCATION << 3) as u8, 1]);
+        let inline = field(TENSOR_NAME, b"b");
+        let graph = [
+            field(GRAPH_INITIALIZER, &tensor),
+            field(GRAPH_INITIALIZER, &inline),
+        ]
+        .concat();
+        let model = field(MODEL_GRAPH, &graph);
+
+        let r = external_initializers(&model).unwrap();
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].name, "w");
+        assert_eq!(r[0].dims, vec![3, 128]);
+        assert_eq!(r[0].elem_type as u32, 1);
+        assert_eq!((r[0].location.as_str(), r[0].offset), ("w.bin", 256));
+        assert_eq!(r[0].length, None);
+    }
+
+    #[test]
+    fn test_model_metadata() {
+        let prop = [field(1, b"kind"), field(2, b"midas")].concat();
+        let graph = [
+            field(GRAPH_NAME, b"torch_jit"),
+            field(GRAPH_INPUT, &field(VALUE_INFO_NAME, b"x")),
+        ]
+        .concat();
+        let model = [
+            field(MODEL_PRODUCER_NAME, b"pytorch"),
+            field(MODEL_DOC_STRING, b"depth"),
+            field(MODEL_GRAPH, &graph),
+            field(MODEL_METADATA_PROPS, &prop),
+        ]
+        .concat();
+
+        let (meta, inputs) = model_metadata(&model).unwrap();
+        assert_eq!(meta.producer, "pytorch");
+        assert_eq!(meta.description, "depth");
+        assert_eq!(meta.graph_name, "torch_jit");
+        assert_eq!(meta.custom["kind"], "midas");
+        assert_eq!(inputs, vec!["x".to_string()]);
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    ffi::CString,
+    path::{Path, PathBuf},
+};
+
+use ort_sys as sys;
+
+use super::ort_call;
+use crate::{
+    ort::get_api,
+    result::{Error, Result},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ExecutionProvider {
+    Cuda { device_id: usize },
+    CoreMl,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GraphOptimizationLevel {
+    Disabled,
+    Basic,
+    Extended,
+    All,
+}
+
+// Options a session is created with. Providers are tried in order and the CPU
+// is always the last fallback.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SessionOptions {
+    providers: Vec<ExecutionProvider>,
+    optimization_level: GraphOptimizationLevel,
+    // without thread counts sessions share the global thread pools
+    intra_op_threads: Option<usize>,
+    inter_op_threads: Option<usize>,
+    config_entries: Vec<(String, String)>,
+    optimized_model_cache: bool,
+    profiling: Option<PathBuf>,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            providers: if cfg!(target_os = "macos") {
+                vec![ExecutionProvider::CoreMl]
+            } else {
+                vec![]
+            },
+            optimization_level: GraphOptimizationLevel::All,
+            intra_op_threads: None,
+            inter_op_threads: None,
+            config_entries: [
+                ("session.use_env_allocators", "1"),
+                ("session.enable_quant_qdq_cleanup", "1"),
+                ("session.strict_shape_type_inference", "1"),
+                ("session.dynamic_block_base", "4"),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+            optimized_model_cache: false,
+            profiling: None,
+        }
+    }
+}
+
+impl SessionOptions {
+    pub fn cpu() -> Self {
+        Self::default().with_providers(vec![])
+    }
+
+    pub fn with_providers(mut self, providers: Vec<ExecutionProvider>) -> Self {
+        self.providers = providers;
+        self
+    }
+
+    pub fn with_provider(mut self, provider: ExecutionProvider) -> Self {
+        self.providers.push(provider);
+        self
+    }
+
+    pub fn with_optimization_level(mut self, level: GraphOptimizationLevel) -> Self {
+        self.optimization_level = level;
+        self
+    }
+
+    pub fn with_intra_op_threads(mut self, n: usize) -> Self {
+        self.intra_op_threads = Some(n);
+        self
+    }
+
+    pub fn with_inter_op_threads(mut self, n: usize) -> Self {
+        self.inter_op_threads = Some(n);
+        self
+    }
+
+    // Sets a session config entry, e.g. "session.dynamic_block_base", replacing an earlier value.
+    pub fn with_config_entry(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        let (key, value) = (key.into(), value.into());
+        if let Some(e) = self.config_entries.iter_mut().find(|(k, _)| *k == key) {
+            e.1 = value;
+        } else {
+            self.config_entries.push((key, value));
+        }
+        self
+    }
+
+    // Stores the optimized graph next to the model and loads it instead of
+    // optimizing again. Sessions with initializer transforms are not cached,
+    // nor are sessions on CoreML, whose compiled nodes ORT can't save.
+    pub fn with_optimized_model_cache(mut self, enabled: bool) -> Self {
+        self.optimized_model_cache = enabled;
+        self
+    }
+
+    // Has ORT profile every run and write the trace into `dir` when the session
+    // ends, for `Session::end_profiling` or `take_profiles` to read.
+    pub fn with_profiling(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.profiling = Some(dir.into());
+        self
+    }
+
+    pub fn profiling(&self) -> Option<&Path> {
+        self.profiling.as_deref()
+    }
+
+    // Profiling doesn't change the optimized graph, so it stays out of cache keys.
+    pub(super) fn without_profiling(&self) -> Self {
+        Self {
+            profiling: None,
+            ..self.clone()
+        }
+    }
+
+    pub fn optimized_model_cache(&self) -> bool {
+        self.optimized_model_cache
+    }
+
+    pub fn providers(&self) -> &[ExecutionProvider] {
+        &self.providers
+    }
+
+    pub fn optimization_level(&self) -> GraphOptimizationLevel {
+        self.optimization_level
+    }
+
+    // Applies the options, returning the CUDA device the session runs on if any.
+    pub(super) fn apply(
+        &self,
+        session_options: *mut sys::OrtSessionOptions,
+    ) -> Result<Option<usize>> {
+        let api = get_api();
+        if self.intra_op_threads.is_none() && self.inter_op_threads.is_none() {
+            ort_call!(api.DisablePerSessionThreads, session_options)?;
+        }
+        if let Some(n) = self.intra_op_threads {
+            ort_call!(api.SetIntraOpNumThreads, session_options, n as _)?;
+        }
+        if let Some(n) = self.inter_op_threads {
+            ort_call!(api.SetInterOpNumThreads, session_options, n as _)?;
+            if n > 1 {
+                ort_call!(
+                    api.SetSessionExecutionMode,
+                    session_options,
+                    sys::ExecutionMode_ORT_PARALLEL,
+                )?;
+            }
+        }
+        self.apply_optimization_level(session_options)?;
+        for (k, v) in &self.config_entries {
+            let k = CString::new(k.as_str())
+                .map_err(|_| Error::InvalidInput(format!("invalid config key {:?}", k)))?;
+            let v = CString::new(v.as_str())
+                .map_err(|_| Error::InvalidInput(format!("invalid config value {:?}", v)))?;
+            ort_call!(
+                api.AddSessionConfigEntry,
+                session_options,
+                k.as_ptr(),
+                v.as_ptr(),
+            )?;
+        }
+
+        let mut cuda_device = None;
+        for p in &self.providers {
+            match p {
+                ExecutionProvider::Cuda { device_id } => {
+                    if cuda_device.is_none() && append_cuda(session_options, *device_id)? {
+                        cuda_device = Some(*device_id);
+                    }
+                }
+                ExecutionProvider::CoreMl => append_coreml(session_options)?,
+            }
+        }
+        Ok(cuda_device)
+    }
+
+    pub(super) fn apply_optimization_level(
+        &self,
+        session_options: *mut sys::OrtSessionOptions,
+    ) -> Result<()> {
+        ort_call!(
+            get_api().SetSessionGraphOptimizationLevel,
+            session_options,
+            match self.optimization_level {
+                GraphOptimizationLevel::Disabled => sys::GraphOptimizationLevel_ORT_DISABLE_ALL,
+                GraphOptimizationLevel::Basic => sys::GraphOptimizationLevel_ORT_ENABLE_BASIC,
+                GraphOptimizationLevel::Extended => {
+                    sys::GraphOptimizationLevel_ORT_ENABLE_EXTENDED
+                }
+                GraphOptimizationLevel::All => sys::GraphOptimizationLevel_ORT_ENABLE_ALL,
+            },
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn append_coreml(session_options: *mut sys::OrtSessionOptions) -> Result<()> {
+    let coreml: u32 = 0;
+    super::status(unsafe {
+        sys::OrtSessionOptionsAppendExecutionProvider_CoreML(session_options, coreml)
+    })?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "macos"))]
+fn append_coreml(_session_options: *mut sys::OrtSessionOptions) -> Result<()> {
+    Err(Error::Unsupported("CoreML execution provider".to_string()))
+}
+
+// Returns false when CUDA is unavailable at runtime, leaving the next provider to run the session.
+#[cfg(all(
+    target_arch = "x86_64",
+    any(target_os = "linux", target_os = "windows")
+))]
+fn append_cuda(session_options: *mut sys::OrtSessionOptions, device_id: usize) -> Result<bool> {
+    let api = get_api();
+    let mut cuda_options: *mut sys::OrtCUDAProviderOptionsV2 = std::ptr::null_mut();
+    if ort_call!(api.CreateCUDAProviderOptions, &mut cuda_options).is_err() {
+        return Ok(false);
+    }
+    defer! {
+        unsafe { api.ReleaseCUDAProviderOptions.unwrap()(cuda_options); }
+    }
+
+    let device_id = CString::new(device_id.to_string()).unwrap();
+    ort_call!(
+        api.UpdateCUDAProviderOptions,
+        cuda_options,
+        [
+            "device_id\0".as_ptr() as *const i8,
+            "arena_extend_strategy\0".as_ptr() as *const i8,
+            "cudnn_conv_use_max_workspace\0".as_ptr() as *const i8,
+            "cudnn_conv1d_pad_to_nc1d\0".as_ptr() as *const i8,
+        ]
+        .as_ptr(),
+        [
+            device_id.as_ptr(),
+            "kSameAsRequested\0".as_ptr() as *const i8,
+            "1\0".as_ptr() as *const i8,
+            "1\0".as_ptr() as *const i8,
+        ]
+        .as_ptr(),
+        4
+    )?;
+
+    Ok(ort_call!(
+        api.SessionOptionsAppendExecutionProvider_CUDA_V2,
+        session_options,
+        cuda_options,
+    )
+    .is_ok())
+}
+
+#[cfg(not(all(
+    target_arch = "x86_64",
+    any(target_os = "linux", target_os = "windows")
+)))]
+fn append_cuda(_session_options: *mut sys::OrtSessionOptions, _device_id: usize) -> Result<bool> {
+    Err(Error::Unsupported("CUDA execution provider".to_string()))
+}
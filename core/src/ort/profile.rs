@@ -0,0 +1,115 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::result::Result;
+
+// Profiles of sessions that ended while profiling, see `take_profiles`.
+static PROFILES: Mutex<Vec<SessionProfile>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Debug)]
+pub struct NodeTiming {
+    pub name: String,
+    pub op_type: String,
+    pub provider: String,
+    pub calls: usize,
+    pub total: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionProfile {
+    pub model: String,
+    pub file: PathBuf,
+    // wall time of every run while profiling
+    pub runs: Vec<Duration>,
+    // slowest first
+    pub nodes: Vec<NodeTiming>,
+}
+
+impl SessionProfile {
+    // Reads the trace ORT wrote to `file`.
+    pub(super) fn read(model: String, file: PathBuf, runs: Vec<Duration>) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Event {
+            cat: String,
+            name: String,
+            #[serde(default)]
+            dur: u64,
+            #[serde(default)]
+            args: HashMap<String, serde_json::Value>,
+        }
+
+        let events: Vec<Event> = serde_json::from_reader(std::fs::File::open(&file)?)?;
+        let mut nodes: HashMap<String, NodeTiming> = HashMap::new();
+        for e in events {
+            // fence events only wait for other nodes
+            let name = match e.name.strip_suffix("_kernel_time") {
+                Some(n) if e.cat == "Node" => n,
+                _ => continue,
+            };
+            let arg = |k: &str| {
+                e.args
+                    .get(k)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            let node = nodes.entry(name.to_string()).or_insert_with(|| NodeTiming {
+                name: name.to_string(),
+                op_type: arg("op_name"),
+                provider: arg("provider"),
+                calls: 0,
+                total: Duration::ZERO,
+            });
+            node.calls += 1;
+            node.total += Duration::from_micros(e.dur);
+        }
+        let mut nodes: Vec<_> = nodes.into_values().collect();
+        nodes.sort_by(|a, b| b.total.cmp(&a.total));
+
+        Ok(Self {
+            model,
+            file,
+            runs,
+            nodes,
+        })
+    }
+
+    // Node timings summed per operator type, slowest first.
+    pub fn op_types(&self) -> Vec<(String, usize, Duration)> {
+        let mut ops: HashMap<&str, (usize, Duration)> = HashMap::new();
+        for n in &self.nodes {
+            let e = ops.entry(&n.op_type).or_default();
+            e.0 += n.calls;
+            e.1 += n.total;
+        }
+        let mut ops: Vec<_> = ops
+            .into_iter()
+            .map(|(k, (calls, total))| (k.to_string(), calls, total))
+            .collect();
+        ops.sort_by(|a, b| b.2.cmp(&a.2));
+        ops
+    }
+}
+
+pub(super) fn collect(profile: SessionProfile) {
+    PROFILES.lock().unwrap().push(profile);
+}
+
+// Profiles of the sessions that were dropped with profiling enabled since the last call.
+pub fn take_profiles() -> Vec<SessionProfile> {
+    std::mem::take(&mut *PROFILES.lock().unwrap())
+}
+
+pub(super) fn model_name(base: &Path, path: &str) -> String {
+    let base = base
+        .file_name()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    format!("{}/{}", base, path)
+}
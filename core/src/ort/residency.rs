@@ -0,0 +1,144 @@
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+use ort_sys as sys;
+
+use crate::ort::get_api;
+
+// Sessions register here with the approximate size of their weights. With a
+// budget set, loading a session first releases the least recently used others
+// until the weights fit; released sessions load again on their next run. The
+// budget counts weights on disk only and is checked when a session loads, so
+// activations and arena growth during runs come on top of it.
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    budget: None,
+    clock: 0,
+    entries: Vec::new(),
+});
+
+struct Registry {
+    budget: Option<usize>,
+    clock: u64,
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    resident: Weak<Resident>,
+    last_used: u64,
+}
+
+// The ORT session of a `Session`, null while released.
+pub(super) struct Resident {
+    pub(super) raw: RwLock<*mut sys::OrtSession>,
+    pub(super) size: usize,
+    // sessions that can't be loaded again, like profiling ones, stay loaded
+    pub(super) evictable: bool,
+}
+
+unsafe impl Send for Resident {}
+
+unsafe impl Sync for Resident {}
+
+impl Resident {
+    pub(super) fn new(raw: *mut sys::OrtSession, size: usize, evictable: bool) -> Arc<Self> {
+        let r = Arc::new(Self {
+            raw: RwLock::new(raw),
+            size,
+            evictable,
+        });
+        let mut reg = REGISTRY.lock().unwrap();
+        reg.clock += 1;
+        let last_used = reg.clock;
+        reg.entries.push(Entry {
+            resident: Arc::downgrade(&r),
+            last_used,
+        });
+        r
+    }
+
+    fn is_loaded(&self) -> bool {
+        self.raw.try_read().map_or(true, |r| !r.is_null())
+    }
+
+    // Releases the session unless it is running.
+    fn release(&self) -> bool {
+        match self.raw.try_write() {
+            Ok(mut raw) if !raw.is_null() => {
+                unsafe { get_api().ReleaseSession.unwrap()(*raw) };
+                *raw = std::ptr::null_mut();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Drop for Resident {
+    fn drop(&mut self) {
+        let raw = *self.raw.get_mut().unwrap();
+        if !raw.is_null() {
+            unsafe { get_api().ReleaseSession.unwrap()(raw) };
+        }
+    }
+}
+
+pub(super) fn touch(resident: &Arc<Resident>) {
+    let mut reg = REGISTRY.lock().unwrap();
+    reg.clock += 1;
+    let clock = reg.clock;
+    if let Some(e) = reg
+        .entries
+        .iter_mut()
+        .find(|e| std::ptr::eq(e.resident.as_ptr(), Arc::as_ptr(resident)))
+    {
+        e.last_used = clock;
+    }
+}
+
+// Releases other sessions until `size` more bytes fit in the budget.
+pub(super) fn make_room(size: usize, except: Option<&Arc<Resident>>) {
+    let mut reg = REGISTRY.lock().unwrap();
+    reg.entries.retain(|e| e.resident.strong_count() > 0);
+    let budget = match reg.budget {
+        Some(b) => b,
+        None => return,
+    };
+
+    let mut loaded: Vec<(u64, Arc<Resident>)> = reg
+        .entries
+        .iter()
+        .filter_map(|e| Some((e.last_used, e.resident.upgrade()?)))
+        .filter(|(_, r)| except.map_or(true, |x| !Arc::ptr_eq(r, x)) && r.is_loaded())
+        .collect();
+    let mut used: usize = loaded.iter().map(|(_, r)| r.size).sum();
+    loaded.sort_by_key(|(t, _)| *t);
+    for (_, r) in loaded {
+        if used + size <= budget {
+            break;
+        }
+        if r.evictable && r.release() {
+            used -= r.size;
+        }
+    }
+}
+
+// Limits the weights of loaded sessions to about `budget` bytes, or lifts the
+// limit with None. A session larger than the budget still loads, alone.
+pub fn set_memory_budget(budget: Option<usize>) {
+    REGISTRY.lock().unwrap().budget = budget;
+    make_room(0, None);
+}
+
+pub fn memory_budget() -> Option<usize> {
+    REGISTRY.lock().unwrap().budget
+}
+
+// Approximate size of the weights of the sessions loaded right now.
+pub fn resident_size() -> usize {
+    let reg = REGISTRY.lock().unwrap();
+    reg.entries
+        .iter()
+        .filter_map(|e| e.resident.upgrade())
+        .filter(|r| r.is_loaded())
+        .map(|r| r.size)
+        .sum()
+}
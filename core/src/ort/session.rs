@@ -1,9 +1,12 @@
 use std::{
-    collections::HashMap,
-    ffi::{c_void, CStr, CString},
-    io::{Read, Seek},
-    path::Path,
-    sync::Mutex,
+    any::Any,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    ffi::{CStr, CString},
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLockReadGuard},
+    time::{Duration, Instant},
 };
 
 use ort_sys as sys;
@@ -14,293 +17,297 @@ use smallvec::{smallvec, SmallVec};
 use super::ort_call;
 use crate::{
     ort::{
+        cache::OptimizedModelCache,
         env::{get_cpu_mem_info, get_env},
         get_api,
+        mapped::MappedArchive,
+        metadata::{read_metadata, ModelMetadata},
+        onnx,
+        profile::{self, SessionProfile},
+        residency::{self, Resident},
+        tensor::{tensor_parts, Tensor},
+        ExecutionProvider, InitializerTransform, SessionOptions,
     },
     result::{Error, Result},
 };
 
 pub struct Session {
-    session: *mut sys::OrtSession,
+    resident: Arc<Resident>,
+    reload: Reload,
     use_cuda: Option<usize>,
     outputs: Vec<CString>,
+    signature: Signature,
+    name: String,
+    profiling: bool,
+    // wall time of the runs while profiling, as other sessions run indefinitely
+    runs: Mutex<Vec<Duration>>,
 }
 
+// What the session was loaded from, to load it again after it was released.
+struct Reload {
+    base: PathBuf,
+    path: String,
+    options: SessionOptions,
+    transforms: Vec<Arc<dyn InitializerTransform>>,
+}
+
+#[derive(Clone, Debug)]
 pub struct TensorInfo {
     pub elem_type: DataType,
-    pub shape: SmallVec<[usize; 4]>,
+    pub shape: SmallVec<[Dim; 4]>,
 }
 
-impl Session {
-    pub fn load(base: impl AsRef<Path>, path: impl AsRef<str>, cpu_only: bool) -> Result<Self> {
-        let base = base.as_ref();
-        let api = get_api();
-        let mut session_options: *mut sys::OrtSessionOptions = std::ptr::null_mut();
-        ort_call!(api.CreateSessionOptions, &mut session_options)?;
-        defer! {
-            unsafe { api.ReleaseSessionOptions.unwrap()(session_options); }
-        }
-        ort_call!(api.DisablePerSessionThreads, session_options)?;
-        ort_call!(
-            api.SetSessionGraphOptimizationLevel,
-            session_options,
-            sys::GraphOptimizationLevel_ORT_ENABLE_ALL,
-        )?;
-        ort_call!(
-            api.AddSessionConfigEntry,
-            session_options,
-            sys::kOrtSessionOptionsConfigUseEnvAllocators as *const _ as *const i8,
-            "1\0".as_ptr() as *const i8,
-        )?;
-        ort_call!(
-            api.AddSessionConfigEntry,
-            session_options,
-            sys::kOrtSessionOptionsEnableQuantQDQCleanup as *const _ as *const i8,
-            "1\0".as_ptr() as *const i8,
-        )?;
-        ort_call!(
-            api.AddSessionConfigEntry,
-            session_options,
-            sys::kOrtSessionOptionsConfigStrictShapeTypeInference as *const _ as *const i8,
-            "1\0".as_ptr() as *const i8,
-        )?;
-        ort_call!(
-            api.AddSessionConfigEntry,
-            session_options,
-            sys::kOrtSessionOptionsConfigDynamicBlockBase as *const _ as *const i8,
-            "4\0".as_ptr() as *const i8,
-        )?;
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Dim {
+    Fixed(usize),
+    // dynamic, named by the model if it gave the dimension a name
+    Symbolic(String),
+}
 
-        #[cfg(target_os = "macos")]
-        if !std::env::var("DISABLE_COREML").is_ok() {
-            let coreml: u32 = 0;
-            super::status(unsafe {
-                sys::OrtSessionOptionsAppendExecutionProvider_CoreML(session_options, coreml)
-            })
-            .unwrap();
+impl std::fmt::Display for Dim {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Dim::Fixed(n) => write!(f, "{}", n),
+            Dim::Symbolic(s) if s.is_empty() => write!(f, "?"),
+            Dim::Symbolic(s) => write!(f, "{}", s),
         }
+    }
+}
 
-        let use_cuda = || -> Option<usize> {
-            if cpu_only {
-                return None;
-            }
+impl TensorInfo {
+    pub fn matches(&self, shape: &[usize]) -> bool {
+        self.shape.len() == shape.len()
+            && self.shape.iter().zip(shape).all(|(d, s)| match d {
+                Dim::Fixed(n) => n == s,
+                Dim::Symbolic(_) => true,
+            })
+    }
+}
 
-            #[cfg(all(
-                target_arch = "x86_64",
-                any(target_os = "linux", target_os = "windows")
-            ))]
-            if std::env::var("USE_CUDA").is_ok() {
-                let mut cuda_options: *mut sys::OrtCUDAProviderOptionsV2 = std::ptr::null_mut();
-                if ort_call!(api.CreateCUDAProviderOptions, &mut cuda_options).is_ok() {
-                    defer! {
-                        unsafe { api.ReleaseCUDAProviderOptions.unwrap()(cuda_options); }
-                    }
+// Inputs and outputs of a session in model order.
+#[derive(Clone, Debug, Default)]
+pub struct Signature {
+    pub inputs: Vec<(String, TensorInfo)>,
+    pub outputs: Vec<(String, TensorInfo)>,
+}
 
-                    if let Err(e) = ort_call!(
-                        api.UpdateCUDAProviderOptions,
-                        cuda_options,
-                        [
-                            "arena_extend_strategy\0".as_ptr() as *const i8,
-                            "cudnn_conv_use_max_workspace\0".as_ptr() as *const i8,
-                            "cudnn_conv1d_pad_to_nc1d\0".as_ptr() as *const i8,
-                        ]
-                        .as_ptr(),
-                        [
-                            "kSameAsRequested\0".as_ptr() as *const i8,
-                            "1\0".as_ptr() as *const i8,
-                            "1\0".as_ptr() as *const i8,
-                        ]
-                        .as_ptr(),
-                        3
-                    ) {
-                        println!("UpdateCUDAProviderOptions failed: {:?}", e);
-                    }
+impl Signature {
+    pub fn input(&self, name: &str) -> Option<&TensorInfo> {
+        self.inputs.iter().find(|(n, _)| n == name).map(|(_, t)| t)
+    }
 
-                    if let Err(e) = ort_call!(
-                        api.SessionOptionsAppendExecutionProvider_CUDA_V2,
-                        session_options,
-                        cuda_options,
-                    ) {
-                        println!(
-                            "SessionOptionsAppendExecutionProvider_CUDA_V2 failed: {:?}",
-                            e
-                        );
-                    } else {
-                        return Some(0);
-                    }
-                }
-            }
-            None
-        }();
+    pub fn output(&self, name: &str) -> Option<&TensorInfo> {
+        self.outputs.iter().find(|(n, _)| n == name).map(|(_, t)| t)
+    }
 
-        struct OrtWeight {
-            name: CString,
-            _data: Vec<u8>,
-            ptr: *mut sys::OrtValue,
+    // Checks an input against the model before running it, so a wrong size
+    // fails with the shape the model expects instead of deep inside ORT.
+    pub fn check_input(&self, name: &str, elem_type: DataType, shape: &[usize]) -> Result<()> {
+        let info = self
+            .input(name)
+            .ok_or_else(|| Error::InvalidInput(format!("input name {} not found", name)))?;
+        if info.elem_type != elem_type {
+            return Err(Error::InvalidInput(format!(
+                "input {} has type {:?}, but model expects {:?}",
+                name, elem_type, info.elem_type
+            )));
         }
-        unsafe impl Send for OrtWeight {}
-
-        let ort_blobs: Mutex<Vec<OrtWeight>> = Mutex::new(Vec::new());
-        defer! {
-            ort_blobs.lock().unwrap().iter().for_each(|o| {
-                unsafe { api.ReleaseValue.unwrap()(o.ptr); }
-            });
+        if !info.matches(shape) {
+            let expected: Vec<String> = info.shape.iter().map(|d| d.to_string()).collect();
+            return Err(Error::InvalidInput(format!(
+                "input {} has shape {:?}, but model expects [{}]",
+                name,
+                shape,
+                expected.join(", ")
+            )));
         }
+        Ok(())
+    }
+}
+
+impl Session {
+    pub fn load(base: impl AsRef<Path>, path: impl AsRef<str>) -> Result<Self> {
+        Self::load_with(base, path, &SessionOptions::default())
+    }
+
+    pub fn load_with(
+        base: impl AsRef<Path>,
+        path: impl AsRef<str>,
+        options: &SessionOptions,
+    ) -> Result<Self> {
+        Self::load_transformed(base, path, options, &[])
+    }
 
-        let mut result = Self {
-            session: std::ptr::null_mut(),
+    // Loads a session, passing the initializers through `transforms` first.
+    pub fn load_transformed(
+        base: impl AsRef<Path>,
+        path: impl AsRef<str>,
+        options: &SessionOptions,
+        transforms: &[Arc<dyn InitializerTransform>],
+    ) -> Result<Self> {
+        let base = base.as_ref();
+        let name = profile::model_name(base, path.as_ref());
+        let size = estimate_size(base, path.as_ref())?;
+        residency::make_room(size, None);
+        let (session, use_cuda) = create(base, path.as_ref(), options, transforms, &name)?;
+        // the trace of a profiling session would end with it, so it stays loaded
+        let profiling = options.profiling().is_some();
+        let resident = Resident::new(session, size, !profiling);
+
+        let signature = read_signature(session)?;
+        let outputs = signature
+            .outputs
+            .iter()
+            .map(|(n, _)| CString::new(n.as_str()).expect("CString::new failed"))
+            .collect();
+        Ok(Self {
+            resident,
+            reload: Reload {
+                base: base.to_path_buf(),
+                path: path.as_ref().to_string(),
+                options: options.without_profiling(),
+                transforms: transforms.to_vec(),
+            },
             use_cuda,
-            outputs: Vec::new(),
-        };
+            outputs,
+            signature,
+            name,
+            profiling,
+            runs: Mutex::new(Vec::new()),
+        })
+    }
 
-        if base.is_file() {
-            let mut t = tsar::Archive::new(std::fs::File::open(base)?)?;
-            load_blobs(&mut t, &format!(".{}.json", path.as_ref()))?
-                .into_par_iter()
-                .try_for_each(|(k, mut v)| -> Result<()> {
-                    let shape: smallvec::SmallVec<[_; 4]> =
-                        v.shape().into_iter().map(|s| s as i64).collect();
-
-                    let mut tmp: Vec<u8> = Vec::new();
-                    v.read_to_end(&mut tmp)?;
-
-                    let mut blob: *mut sys::OrtValue = std::ptr::null_mut();
-                    ort_call!(
-                        api.CreateTensorWithDataAsOrtValue,
-                        get_cpu_mem_info(),
-                        tmp.as_mut_ptr() as *mut _,
-                        tmp.len() as _,
-                        shape.as_ptr() as *const _,
-                        shape.len() as _,
-                        datatype_to_onnx(v.data_type()),
-                        &mut blob
-                    )?;
-
-                    ort_blobs.lock().unwrap().push(OrtWeight {
-                        name: CString::new(k).expect("CString::new failed"),
-                        _data: tmp,
-                        ptr: blob,
-                    });
-                    Ok(())
-                })?;
+    // The ORT session, loaded again if the residency manager released it.
+    // Holding the guard keeps it from being released.
+    fn acquire(&self) -> Result<RwLockReadGuard<'_, *mut sys::OrtSession>> {
+        residency::touch(&self.resident);
+        loop {
+            let raw = self.resident.raw.read().unwrap();
+            if !raw.is_null() {
+                return Ok(raw);
+            }
+            drop(raw);
 
-            ort_blobs.lock().unwrap().iter().try_for_each(|o| {
-                ort_call!(
-                    api.AddExternalInitializers,
-                    session_options,
-                    &o.name.as_ptr(),
-                    &(o.ptr as *const _),
-                    1,
-                )
-            })?;
+            let mut raw = self.resident.raw.write().unwrap();
+            if raw.is_null() {
+                residency::make_room(self.resident.size, Some(&self.resident));
+                let r = &self.reload;
+                *raw = create(&r.base, &r.path, &r.options, &r.transforms, &self.name)?.0;
+            }
+        }
+    }
 
-            let onnx = {
-                let mut data = Vec::new();
-                t.file_by_name(path)?.read_to_end(&mut data)?;
-                data
-            };
-            ort_call!(
-                api.CreateSessionFromArray,
-                get_env(),
-                onnx.as_ptr() as *const _,
-                onnx.len() as _,
-                session_options,
-                &mut result.session,
-            )?;
-        } else {
-            let onnx = path_to_cstring(base.join(path.as_ref()));
-            ort_call!(
-                api.CreateSession,
-                get_env(),
-                onnx.as_ptr(),
-                session_options,
-                &mut result.session,
-            )?;
+    // Wall time of every run since profiling started.
+    pub fn run_times(&self) -> Vec<Duration> {
+        self.runs.lock().unwrap().clone()
+    }
+
+    // Stops profiling and reads the trace, if the session was profiling.
+    pub fn end_profiling(&mut self) -> Result<Option<SessionProfile>> {
+        match self.stop_profiling()? {
+            Some((file, runs)) => Ok(Some(SessionProfile::read(self.name.clone(), file, runs)?)),
+            None => Ok(None),
         }
+    }
 
-        let mut out_len = 0;
-        ort_call!(api.SessionGetOutputCount, result.session, &mut out_len)?;
+    // Stops profiling, returning the trace ORT wrote and the run times.
+    fn stop_profiling(&mut self) -> Result<Option<(PathBuf, Vec<Duration>)>> {
+        if !self.profiling {
+            return Ok(None);
+        }
+        self.profiling = false;
 
+        let api = get_api();
         let mut alloc: *mut sys::OrtAllocator = std::ptr::null_mut();
         ort_call!(api.GetAllocatorWithDefaultOptions, &mut alloc)?;
-        for i in 0..out_len {
-            let mut name: *mut i8 = std::ptr::null_mut();
-            ort_call!(
-                api.SessionGetOutputName,
-                result.session,
-                i,
-                alloc,
-                &mut name,
-            )?;
-            result
-                .outputs
-                .push(unsafe { CStr::from_ptr(name) }.to_owned());
-            unsafe {
-                (*alloc).Free.unwrap()(alloc, name as *mut _);
-            }
+        let session = self.acquire()?;
+        let mut cfile: *mut i8 = std::ptr::null_mut();
+        ort_call!(api.SessionEndProfiling, *session, alloc, &mut cfile)?;
+        let file = unsafe { CStr::from_ptr(cfile) }
+            .to_string_lossy()
+            .into_owned();
+        unsafe {
+            (*alloc).Free.unwrap()(alloc, cfile as *mut _);
         }
+        Ok(Some((
+            file.into(),
+            std::mem::take(&mut *self.runs.lock().unwrap()),
+        )))
+    }
 
-        Ok(result)
+    fn record_run(&self, start: Instant) {
+        if self.profiling {
+            self.runs.lock().unwrap().push(start.elapsed());
+        }
     }
 
     pub fn prepare(&self) -> SessionRun<'_> {
         SessionRun {
             sess: self,
             inputs: SmallVec::new(),
+            allocated: SmallVec::new(),
         }
     }
 
-    pub fn inputs(&self) -> Result<HashMap<String, TensorInfo>> {
-        let api = get_api();
-        let mut in_len = 0;
-        ort_call!(api.SessionGetInputCount, self.session, &mut in_len)?;
+    fn output_index(&self, name: &str) -> Result<usize> {
+        self.outputs
+            .iter()
+            .position(|c| c.as_bytes() == name.as_bytes())
+            .ok_or_else(|| Error::InvalidInput(format!("output name {} not found", name)))
+    }
 
-        let mut alloc: *mut sys::OrtAllocator = std::ptr::null_mut();
-        ort_call!(api.GetAllocatorWithDefaultOptions, &mut alloc)?;
-        let mut result = HashMap::new();
-        for i in 0..in_len {
-            let mut cname: *mut i8 = std::ptr::null_mut();
-            ort_call!(api.SessionGetInputName, self.session, i, alloc, &mut cname)?;
-            let name = unsafe { CStr::from_ptr(cname) }
-                .to_string_lossy()
-                .into_owned();
-            unsafe {
-                (*alloc).Free.unwrap()(alloc, cname as *mut _);
-            }
+    pub fn signature(&self) -> &Signature {
+        &self.signature
+    }
 
-            let mut type_info: *mut sys::OrtTypeInfo = std::ptr::null_mut();
-            ort_call!(api.SessionGetInputTypeInfo, self.session, i, &mut type_info)?;
-            defer! {
-                unsafe { api.ReleaseTypeInfo.unwrap()(type_info); }
-            };
+    pub fn metadata(&self) -> Result<ModelMetadata> {
+        read_metadata(*self.acquire()?)
+    }
 
-            let mut tensor_shape: *const sys::OrtTensorTypeAndShapeInfo = std::ptr::null_mut();
-            ort_call!(api.CastTypeInfoToTensorInfo, type_info, &mut tensor_shape)?;
+    pub fn inputs(&self) -> Result<HashMap<String, TensorInfo>> {
+        Ok(self.signature.inputs.iter().cloned().collect())
+    }
 
-            let mut elem_type: sys::ONNXTensorElementDataType =
-                sys::ONNXTensorElementDataType_ONNX_TENSOR_ELEMENT_DATA_TYPE_UNDEFINED;
-            ort_call!(api.GetTensorElementType, tensor_shape, &mut elem_type)?;
+    pub fn output_types(&self) -> Result<HashMap<String, TensorInfo>> {
+        Ok(self.signature.outputs.iter().cloned().collect())
+    }
 
-            let mut dims = 0;
-            ort_call!(api.GetDimensionsCount, tensor_shape, &mut dims)?;
+    // Binds inputs and outputs to buffers of the caller, so repeated runs
+    // don't allocate outputs or copy them out of ORT.
+    pub fn bind(&self) -> Result<IoBinding<'_>> {
+        let session = self.acquire()?;
+        let mut binding: *mut sys::OrtIoBinding = std::ptr::null_mut();
+        ort_call!(get_api().CreateIoBinding, *session, &mut binding)?;
+        Ok(IoBinding {
+            sess: self,
+            session,
+            binding,
+            values: SmallVec::new(),
+        })
+    }
 
-            let mut shape: SmallVec<[i64; 4]> = smallvec![0; dims];
-            ort_call!(
-                api.GetDimensions,
-                tensor_shape,
-                shape.as_mut_ptr() as *mut _,
-                dims
-            )?;
+    fn run_options(&self, shrink: bool) -> Result<*mut sys::OrtRunOptions> {
+        let api = get_api();
+        let mut run_options: *mut sys::OrtRunOptions = std::ptr::null_mut();
+        ort_call!(api.CreateRunOptions, &mut run_options)?;
 
-            result.insert(
-                name,
-                TensorInfo {
-                    elem_type: DataType::from(elem_type),
-                    shape: shape.iter().map(|s| *s as usize).collect(),
-                },
-            );
+        if shrink {
+            let cfg = if let Some(g) = self.use_cuda {
+                CString::new(format!("cpu:0;gpu:{}", g)).unwrap()
+            } else {
+                CString::new("cpu:0").unwrap()
+            };
+            if let Err(e) = ort_call!(
+                api.AddRunConfigEntry,
+                run_options,
+                ort_sys::kOrtRunOptionsConfigEnableMemoryArenaShrinkage as *const _ as *const i8,
+                cfg.as_ptr(),
+            ) {
+                unsafe { api.ReleaseRunOptions.unwrap()(run_options) };
+                return Err(e.into());
+            }
         }
-        Ok(result)
+        Ok(run_options)
     }
 
     pub fn outputs(&self) -> Result<Vec<String>> {
@@ -313,12 +320,19 @@ impl Session {
 }
 
 impl Drop for Session {
+    // the ORT session is released with the last reference to `resident`
     fn drop(&mut self) {
-        let api = get_api();
-        if !self.session.is_null() {
-            unsafe {
-                api.ReleaseSession.unwrap()(self.session);
-            }
+        if let Ok(Some((file, runs))) = self.stop_profiling() {
+            // a trace that can't be read is still handed out, without node timings
+            let p = SessionProfile::read(self.name.clone(), file.clone(), runs.clone()).unwrap_or(
+                SessionProfile {
+                    model: self.name.clone(),
+                    file,
+                    runs,
+                    nodes: vec![],
+                },
+            );
+            profile::collect(p);
         }
     }
 }
@@ -330,6 +344,14 @@ unsafe impl Sync for Session {}
 pub struct SessionRun<'s> {
     sess: &'s Session,
     inputs: SmallVec<[(CString, *mut sys::OrtValue); 4]>,
+    allocated: SmallVec<[Allocated; 1]>,
+}
+
+// An output buffer allocated by the caller, which ORT writes into in place.
+struct Allocated {
+    idx: usize,
+    value: *mut sys::OrtValue,
+    array: Box<dyn Any>,
 }
 
 impl<'s> SessionRun<'s> {
@@ -343,25 +365,12 @@ impl<'s> SessionRun<'s> {
         S::Elem: AsOnnxDataType,
         D: ndarray::Dimension,
     {
-        let shape: smallvec::SmallVec<[_; 4]> = data.shape().iter().map(|s| *s as i64).collect();
-        if !data.is_standard_layout() {
-            return Err(Error::InvalidInput(
-                "ndarray is not standard layout".to_string(),
-            ));
-        }
-
-        let mut blob: *mut sys::OrtValue = std::ptr::null_mut();
-        ort_call!(
-            get_api().CreateTensorWithDataAsOrtValue,
-            get_cpu_mem_info(),
-            data.as_ptr() as *mut _,
-            (data.raw_dim().size() * std::mem::size_of::<S::Elem>()) as _,
-            shape.as_ptr(),
-            shape.len() as _,
-            S::Elem::as_onnx_data_type(),
-            &mut blob
+        self.sess.signature.check_input(
+            name.as_ref(),
+            S::Elem::as_onnx_data_type().into(),
+            data.shape(),
         )?;
-
+        let blob = tensor_from_array(data)?;
         self.inputs.push((
             CString::new(name.as_ref()).expect("CString::new failed"),
             blob,
@@ -370,6 +379,28 @@ impl<'s> SessionRun<'s> {
         Ok(())
     }
 
+    // Allocates output `name` with `shape` up front, so `take_output` can hand
+    // it out without a copy. The shape must match what the model produces.
+    pub fn alloc_output<A>(&mut self, name: impl AsRef<str>, shape: &[usize]) -> Result<()>
+    where
+        A: AsOnnxDataType + Clone + Default + 'static,
+    {
+        let idx = self.sess.output_index(name.as_ref())?;
+        let mut array = ndarray::ArrayD::from_elem(shape, A::default());
+        // the boxed array keeps its heap buffer, so the value stays valid
+        let value = tensor_from_array_mut(&mut array)?;
+        if let Some(i) = self.allocated.iter().position(|a| a.idx == idx) {
+            let old = self.allocated.swap_remove(i);
+            unsafe { get_api().ReleaseValue.unwrap()(old.value) };
+        }
+        self.allocated.push(Allocated {
+            idx,
+            value,
+            array: Box::new(array),
+        });
+        Ok(())
+    }
+
     pub fn exec(&mut self, shrink: bool) -> Result<SessionRunResult<'_>> {
         let input_names: SmallVec<[*const i8; 4]> =
             self.inputs.iter().map(|(n, _)| n.as_ptr()).collect();
@@ -381,37 +412,20 @@ impl<'s> SessionRun<'s> {
 
         let mut outputs: SmallVec<[*mut sys::OrtValue; 4]> = SmallVec::new();
         outputs.resize(self.sess.outputs.len(), std::ptr::null_mut());
+        for a in &self.allocated {
+            outputs[a.idx] = a.value;
+        }
 
-        let mut run_options: *mut sys::OrtRunOptions = std::ptr::null_mut();
-        ort_call!(api.CreateRunOptions, &mut run_options)?;
+        let run_options = self.sess.run_options(shrink)?;
         defer!(unsafe {
             api.ReleaseRunOptions.unwrap()(run_options);
         });
 
-        if shrink {
-            if let Some(g) = self.sess.use_cuda {
-                let cfg = CString::new(format!("cpu:0;gpu:{}", g)).unwrap();
-                ort_call!(
-                    api.AddRunConfigEntry,
-                    run_options,
-                    ort_sys::kOrtRunOptionsConfigEnableMemoryArenaShrinkage as *const _
-                        as *const i8,
-                    cfg.as_ptr(),
-                )?;
-            } else {
-                ort_call!(
-                    api.AddRunConfigEntry,
-                    run_options,
-                    ort_sys::kOrtRunOptionsConfigEnableMemoryArenaShrinkage as *const _
-                        as *const i8,
-                    "cpu:0\0".as_ptr() as *const i8,
-                )?;
-            }
-        }
-
+        let session = self.sess.acquire()?;
+        let start = Instant::now();
         ort_call!(
             api.Run,
-            self.sess.session,
+            *session,
             run_options,
             input_names.as_ptr(),
             input_values.as_ptr(),
@@ -420,8 +434,18 @@ impl<'s> SessionRun<'s> {
             self.sess.outputs.len() as _,
             outputs.as_mut_ptr(),
         )?;
-
-        Ok(SessionRunResult { run: self, outputs })
+        self.sess.record_run(start);
+
+        // the values are released with the result from here on
+        let allocated = std::mem::take(&mut self.allocated)
+            .into_iter()
+            .map(|a| (a.idx, a.array))
+            .collect();
+        Ok(SessionRunResult {
+            run: self,
+            outputs,
+            allocated,
+        })
     }
 }
 
@@ -433,12 +457,96 @@ impl Drop for SessionRun<'_> {
                 api.ReleaseValue.unwrap()(inp.1);
             }
         }
+        for a in &self.allocated {
+            unsafe {
+                api.ReleaseValue.unwrap()(a.value);
+            }
+        }
+    }
+}
+
+pub struct IoBinding<'s> {
+    sess: &'s Session,
+    // keeps the session loaded while bound
+    session: RwLockReadGuard<'s, *mut sys::OrtSession>,
+    binding: *mut sys::OrtIoBinding,
+    values: SmallVec<[*mut sys::OrtValue; 4]>,
+}
+
+impl<'s> IoBinding<'s> {
+    pub fn bind_input<S, D>(
+        &mut self,
+        name: impl AsRef<str>,
+        data: &'s ndarray::ArrayBase<S, D>,
+    ) -> Result<()>
+    where
+        S: ndarray::RawData,
+        S::Elem: AsOnnxDataType,
+        D: ndarray::Dimension,
+    {
+        self.sess.signature.check_input(
+            name.as_ref(),
+            S::Elem::as_onnx_data_type().into(),
+            data.shape(),
+        )?;
+        let value = tensor_from_array(data)?;
+        self.values.push(value);
+        let name = CString::new(name.as_ref()).expect("CString::new failed");
+        ort_call!(get_api().BindInput, self.binding, name.as_ptr(), value)?;
+        Ok(())
+    }
+
+    // Has runs write the output into `data`, which must have the output's shape.
+    pub fn bind_output<S, D>(
+        &mut self,
+        name: impl AsRef<str>,
+        data: &'s mut ndarray::ArrayBase<S, D>,
+    ) -> Result<()>
+    where
+        S: ndarray::DataMut,
+        S::Elem: AsOnnxDataType,
+        D: ndarray::Dimension,
+    {
+        let value = tensor_from_array_mut(data)?;
+        self.values.push(value);
+        let name = CString::new(name.as_ref()).expect("CString::new failed");
+        ort_call!(get_api().BindOutput, self.binding, name.as_ptr(), value)?;
+        Ok(())
+    }
+
+    pub fn run(&mut self, shrink: bool) -> Result<()> {
+        let api = get_api();
+        let run_options = self.sess.run_options(shrink)?;
+        defer!(unsafe {
+            api.ReleaseRunOptions.unwrap()(run_options);
+        });
+
+        let start = Instant::now();
+        ort_call!(api.RunWithBinding, *self.session, run_options, self.binding,)?;
+        self.sess.record_run(start);
+        Ok(())
+    }
+}
+
+impl Drop for IoBinding<'_> {
+    fn drop(&mut self) {
+        let api = get_api();
+        unsafe {
+            api.ReleaseIoBinding.unwrap()(self.binding);
+        }
+        for v in &self.values {
+            unsafe {
+                api.ReleaseValue.unwrap()(*v);
+            }
+        }
     }
 }
 
 pub struct SessionRunResult<'s> {
     run: &'s SessionRun<'s>,
+    // null once taken
     outputs: SmallVec<[*mut sys::OrtValue; 4]>,
+    allocated: SmallVec<[(usize, Box<dyn Any>); 1]>,
 }
 
 impl<'s> SessionRunResult<'s> {
@@ -447,22 +555,7 @@ impl<'s> SessionRunResult<'s> {
         A: AsOnnxDataType,
         D: ndarray::Dimension,
     {
-        let name = name.as_ref();
-        let id = self
-            .run
-            .sess
-            .outputs
-            .iter()
-            .enumerate()
-            .find(|(_, c)| c.as_bytes() == name.as_bytes());
-        if let Some((id, _)) = id {
-            self.get_output_idx(id)
-        } else {
-            Err(Error::InvalidInput(format!(
-                "output name {} not found",
-                name
-            )))
-        }
+        self.get_output_idx(self.run.sess.output_index(name.as_ref())?)
     }
 
     pub fn get_output_idx<A, D>(&self, idx: usize) -> Result<ndarray::ArrayView<A, D>>
@@ -470,69 +563,326 @@ impl<'s> SessionRunResult<'s> {
         A: AsOnnxDataType,
         D: ndarray::Dimension,
     {
-        if idx >= self.outputs.len() {
-            return Err(Error::InvalidInput(format!(
+        let out = self.output_value(idx)?;
+        let (data, dim) = tensor_parts(out)?;
+        Ok(unsafe { ndarray::ArrayView::<A, D>::from_shape_ptr(dim, data) })
+    }
+
+    // Moves the output out of the run, so it outlives it without a copy.
+    pub fn take_output<A, D>(&mut self, name: impl AsRef<str>) -> Result<Tensor<A, D>>
+    where
+        A: AsOnnxDataType + 'static,
+        D: ndarray::Dimension + 'static,
+    {
+        self.take_output_idx(self.run.sess.output_index(name.as_ref())?)
+    }
+
+    pub fn take_output_idx<A, D>(&mut self, idx: usize) -> Result<Tensor<A, D>>
+    where
+        A: AsOnnxDataType + 'static,
+        D: ndarray::Dimension + 'static,
+    {
+        let value = self.output_value(idx)?;
+        self.outputs[idx] = std::ptr::null_mut();
+
+        if let Some(i) = self.allocated.iter().position(|(j, _)| *j == idx) {
+            let (_, array) = self.allocated.swap_remove(i);
+            unsafe { get_api().ReleaseValue.unwrap()(value) };
+            let array = array
+                .downcast::<ndarray::ArrayD<A>>()
+                .map_err(|_| Error::InvalidInput(format!("invalid output type for {}", idx)))?
+                .into_dimensionality::<D>()
+                .map_err(|_| {
+                    Error::InvalidInput(format!("invalid output dimension for {}", idx))
+                })?;
+            return Ok(Tensor::from_array(array));
+        }
+        Tensor::from_value(value)
+    }
+
+    fn output_value(&self, idx: usize) -> Result<*mut sys::OrtValue> {
+        match self.outputs.get(idx) {
+            None => Err(Error::InvalidInput(format!(
                 "output index {} out of range",
                 idx
-            )));
+            ))),
+            Some(v) if v.is_null() => Err(Error::InvalidInput(format!(
+                "output {} was already taken",
+                idx
+            ))),
+            Some(v) => Ok(*v),
         }
-        let out = self.outputs[idx];
+    }
+}
 
+impl Drop for SessionRunResult<'_> {
+    fn drop(&mut self) {
         let api = get_api();
-        let mut data: *mut c_void = std::ptr::null_mut();
-        ort_call!(api.GetTensorMutableData, out, &mut data)?;
-
-        let mut info: *mut sys::OrtTensorTypeAndShapeInfo = std::ptr::null_mut();
-        ort_call!(api.GetTensorTypeAndShape, out, &mut info)?;
-        defer! {
-            unsafe { api.ReleaseTensorTypeAndShapeInfo.unwrap()(info); }
-        }
-
-        let mut dims = 0;
-        ort_call!(api.GetDimensionsCount, info, &mut dims)?;
-        if let Some(d) = D::NDIM {
-            if dims != d {
-                return Err(Error::InvalidInput(format!(
-                    "invalid output dimension for {}",
-                    idx,
-                )));
+        for &out in self.outputs.iter().filter(|v| !v.is_null()) {
+            unsafe {
+                api.ReleaseValue.unwrap()(out);
+            }
+        }
+    }
+}
+
+// Creates the ORT session of `path` in `base`, along with the CUDA device it runs on.
+fn create(
+    base: &Path,
+    path: &str,
+    options: &SessionOptions,
+    transforms: &[Arc<dyn InitializerTransform>],
+    name: &str,
+) -> Result<(*mut sys::OrtSession, Option<usize>)> {
+    if let Some(created) = create_session(base, path, options, transforms, name)? {
+        return Ok(created);
+    }
+    // ORT can't save every optimized graph, so load once more without storing it
+    let options = options.clone().with_optimized_model_cache(false);
+    create_session(base, path, &options, transforms, name)?
+        .ok_or_else(|| Error::Unsupported(format!("cannot load {}", name)))
+}
+
+// None when the optimized graph couldn't be stored.
+fn create_session(
+    base: &Path,
+    path: &str,
+    options: &SessionOptions,
+    transforms: &[Arc<dyn InitializerTransform>],
+    name: &str,
+) -> Result<Option<(*mut sys::OrtSession, Option<usize>)>> {
+    let api = get_api();
+    let mut session_options: *mut sys::OrtSessionOptions = std::ptr::null_mut();
+    ort_call!(api.CreateSessionOptions, &mut session_options)?;
+    defer! {
+        unsafe { api.ReleaseSessionOptions.unwrap()(session_options); }
+    }
+    let use_cuda = options.apply(session_options)?;
+    if let Some(dir) = options.profiling() {
+        std::fs::create_dir_all(dir)?;
+        // ORT appends the start time and .json to the prefix
+        let prefix = path_to_cstring(dir.join(name.replace(['/', '\\'], "_")));
+        ort_call!(api.EnableProfiling, session_options, prefix.as_ptr())?;
+    }
+
+    struct OrtWeight<'a> {
+        name: CString,
+        _data: Cow<'a, [u8]>,
+        ptr: *mut sys::OrtValue,
+    }
+    unsafe impl Send for OrtWeight<'_> {}
+
+    // outlives the weights that borrow from it
+    let mapped = if base.is_file() {
+        MappedArchive::open(base)
+    } else {
+        None
+    };
+
+    let ort_blobs: Mutex<Vec<OrtWeight>> = Mutex::new(Vec::new());
+    defer! {
+        ort_blobs.lock().unwrap().iter().for_each(|o| {
+            unsafe { api.ReleaseValue.unwrap()(o.ptr); }
+        });
+    }
+
+    // initializers the transforms rewrote
+    let transformed: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    let add_blob = |name: String,
+                    shape: &[usize],
+                    elem_type: sys::ONNXTensorElementDataType,
+                    data|
+     -> Result<()> {
+        // annotated here rather than on the closure, so the mapped blobs
+        // share one lifetime with `ort_blobs`
+        let mut data: Cow<[u8]> = data;
+        let mut shape = shape.to_vec();
+        for t in transforms {
+            if t.applies(&name, &shape) {
+                t.apply(&name, elem_type.into(), &mut shape, data.to_mut())?;
+                transformed.lock().unwrap().insert(name.clone());
             }
         }
+        let shape: smallvec::SmallVec<[_; 4]> = shape.iter().map(|s| *s as i64).collect();
 
-        let mut shape: SmallVec<[i64; 4]> = SmallVec::new();
-        shape.resize(dims, 0);
-        ort_call!(api.GetDimensions, info, shape.as_mut_ptr() as *mut _, dims)?;
+        let mut blob: *mut sys::OrtValue = std::ptr::null_mut();
+        ort_call!(
+            api.CreateTensorWithDataAsOrtValue,
+            get_cpu_mem_info(),
+            data.as_ptr() as *mut _,
+            data.len() as _,
+            shape.as_ptr() as *const _,
+            shape.len() as _,
+            elem_type,
+            &mut blob
+        )?;
 
-        let mut ty = sys::ONNXTensorElementDataType_ONNX_TENSOR_ELEMENT_DATA_TYPE_UNDEFINED;
-        ort_call!(api.GetValueType, out, &mut ty)?;
-        if A::as_onnx_data_type() != ty {
+        ort_blobs.lock().unwrap().push(OrtWeight {
+            name: CString::new(name).expect("CString::new failed"),
+            _data: data,
+            ptr: blob,
+        });
+        Ok(())
+    };
+    let add_initializers = || -> Result<()> {
+        let transformed = transformed.lock().unwrap();
+        if let Some(missing) = transforms
+            .iter()
+            .flat_map(|t| t.required())
+            .find(|n| !transformed.contains(*n))
+        {
             return Err(Error::InvalidInput(format!(
-                "invalid output type for {}",
-                idx,
+                "{} has no external initializer {} to transform",
+                name, missing
             )));
         }
+        ort_blobs.lock().unwrap().iter().try_for_each(|o| {
+            ort_call!(
+                api.AddExternalInitializers,
+                session_options,
+                &o.name.as_ptr(),
+                &(o.ptr as *const _),
+                1,
+            )
+        })?;
+        Ok(())
+    };
+
+    let mut session: *mut sys::OrtSession = std::ptr::null_mut();
+    // ORT can't save a graph with nodes compiled by CoreML
+    let compiled = options.providers().contains(&ExecutionProvider::CoreMl);
+    let cache = if options.optimized_model_cache() && transforms.is_empty() && !compiled {
+        Some(OptimizedModelCache::new(base, path, options)?)
+    } else {
+        None
+    };
+    let cached = if let Some(c) = &cache {
+        c.load(session_options, options)?
+    } else {
+        None
+    };
+    let write_cache = match (&cache, cached) {
+        (Some(c), None) => c.prepare(session_options)?,
+        _ => false,
+    };
+
+    let created = if let Some(s) = cached {
+        session = s;
+        Ok(())
+    } else if base.is_file() {
+        let mut t = tsar::Archive::new(std::fs::File::open(base)?)?;
+        load_blobs(&mut t, &format!(".{}.json", path))?
+            .into_par_iter()
+            .try_for_each(|(k, (name, mut v))| -> Result<()> {
+                let shape: smallvec::SmallVec<[_; 4]> =
+                    v.shape().into_iter().map(|s| s as usize).collect();
+
+                let data = match (&mapped, v.byte_len()) {
+                    (Some(m), Some(len)) => {
+                        let elem_size = len / shape.iter().product::<usize>().max(1);
+                        let identity = v.is_identity();
+                        m.read(&name, &mut v, len, elem_size, identity)?
+                    }
+                    _ => {
+                        let mut tmp: Vec<u8> = Vec::new();
+                        v.read_to_end(&mut tmp)?;
+                        Cow::Owned(tmp)
+                    }
+                };
+                add_blob(k, &shape, datatype_to_onnx(v.data_type()), data)
+            })?;
+        add_initializers()?;
+
+        let onnx = {
+            let mut data = Vec::new();
+            t.file_by_name(path)?.read_to_end(&mut data)?;
+            data
+        };
+        ort_call!(
+            api.CreateSessionFromArray,
+            get_env(),
+            onnx.as_ptr() as *const _,
+            onnx.len() as _,
+            session_options,
+            &mut session,
+        )
+    } else {
+        let onnx_path = base.join(path);
+        if !transforms.is_empty() {
+            // external initializers given to the session take the place of the data on disk
+            let dir = onnx_path.parent().unwrap_or(base);
+            onnx::external_initializers(&std::fs::read(&onnx_path)?)?
+                .into_par_iter()
+                .filter(|i| transforms.iter().any(|t| t.applies(&i.name, &i.dims)))
+                .try_for_each(|i| -> Result<()> {
+                    let mut f = std::fs::File::open(dir.join(&i.location))?;
+                    f.seek(SeekFrom::Start(i.offset))?;
+                    let mut data = Vec::new();
+                    if let Some(n) = i.length {
+                        data.resize(n as usize, 0);
+                        f.read_exact(&mut data)?;
+                    } else {
+                        f.read_to_end(&mut data)?;
+                    }
+                    add_blob(i.name, &i.dims, i.elem_type, Cow::Owned(data))
+                })?;
+            add_initializers()?;
+        }
 
-        let mut d = D::zeros(shape.len());
-        let mut v = d.as_array_view_mut();
-        for (i, o) in shape.iter().enumerate() {
-            v[i] = *o as usize;
+        let onnx = path_to_cstring(onnx_path);
+        ort_call!(
+            api.CreateSession,
+            get_env(),
+            onnx.as_ptr(),
+            session_options,
+            &mut session,
+        )
+    };
+    if let Err(e) = created {
+        if !write_cache {
+            return Err(e.into());
         }
-        Ok(unsafe { ndarray::ArrayView::<A, D>::from_shape_ptr(d, data as *mut _) })
+        // the caller loads again without the cache, and reports that outcome
+        cache.as_ref().unwrap().discard();
+        return Ok(None);
     }
+    if write_cache {
+        if let Err(e) = cache.as_ref().unwrap().commit() {
+            unsafe { api.ReleaseSession.unwrap()(session) };
+            return Err(e);
+        }
+    }
+
+    Ok(Some((session, use_cuda)))
 }
 
-impl Drop for SessionRunResult<'_> {
-    fn drop(&mut self) {
-        let api = get_api();
-        for &out in &self.outputs {
-            unsafe {
-                api.ReleaseValue.unwrap()(out);
-            }
-        }
+// Approximate memory of the session: the size of its weights on disk.
+fn estimate_size(base: &Path, path: &str) -> Result<usize> {
+    if base.is_file() {
+        let mut t = tsar::Archive::new(std::fs::File::open(base)?)?;
+        let blobs = load_blobs(&mut t, &format!(".{}.json", path))?;
+        return Ok(blobs.values().filter_map(|(_, b)| b.byte_len()).sum());
     }
+
+    let onnx_path = base.join(path);
+    let len = std::fs::metadata(&onnx_path)?.len() as usize;
+    // a large graph holds its weights itself, and only small ones are worth parsing
+    if len > 64 << 20 {
+        return Ok(len);
+    }
+    let dir = onnx_path.parent().unwrap_or(base);
+    let external = onnx::external_initializers(&std::fs::read(&onnx_path)?)?
+        .iter()
+        .map(|i| match i.length {
+            Some(n) => Ok(n as usize),
+            None => Ok((std::fs::metadata(dir.join(&i.location))?.len() - i.offset) as usize),
+        })
+        .sum::<Result<usize>>()?;
+    Ok(len + external)
 }
 
-fn load_blobs<R>(t: &mut tsar::Archive<R>, p: &str) -> Result<HashMap<String, tsar::Blob>>
+// Blobs of a graph by initializer, along with their names in the archive.
+fn load_blobs<R>(t: &mut tsar::Archive<R>, p: &str) -> Result<HashMap<String, (String, tsar::Blob)>>
 where
     R: Read + Seek,
 {
@@ -546,10 +896,163 @@ where
     };
     meta.blobs
         .into_iter()
-        .map(|(k, v)| Ok((k, t.blob_by_name(v)?)))
+        .map(|(k, v)| {
+            let blob = t.blob_by_name(&v)?;
+            Ok((k, (v, blob)))
+        })
         .collect()
 }
 
+fn read_signature(session: *mut sys::OrtSession) -> Result<Signature> {
+    let api = get_api();
+    let mut alloc: *mut sys::OrtAllocator = std::ptr::null_mut();
+    ort_call!(api.GetAllocatorWithDefaultOptions, &mut alloc)?;
+    let name = |name: *mut i8| {
+        let s = unsafe { CStr::from_ptr(name) }
+            .to_string_lossy()
+            .into_owned();
+        unsafe {
+            (*alloc).Free.unwrap()(alloc, name as *mut _);
+        }
+        s
+    };
+
+    let mut signature = Signature::default();
+    let mut in_len = 0;
+    ort_call!(api.SessionGetInputCount, session, &mut in_len)?;
+    for i in 0..in_len {
+        let mut cname: *mut i8 = std::ptr::null_mut();
+        ort_call!(api.SessionGetInputName, session, i, alloc, &mut cname)?;
+        let cname = name(cname);
+        let mut type_info: *mut sys::OrtTypeInfo = std::ptr::null_mut();
+        ort_call!(api.SessionGetInputTypeInfo, session, i, &mut type_info)?;
+        signature.inputs.push((cname, tensor_info(type_info)?));
+    }
+
+    let mut out_len = 0;
+    ort_call!(api.SessionGetOutputCount, session, &mut out_len)?;
+    for i in 0..out_len {
+        let mut cname: *mut i8 = std::ptr::null_mut();
+        ort_call!(api.SessionGetOutputName, session, i, alloc, &mut cname)?;
+        let cname = name(cname);
+        let mut type_info: *mut sys::OrtTypeInfo = std::ptr::null_mut();
+        ort_call!(api.SessionGetOutputTypeInfo, session, i, &mut type_info)?;
+        signature.outputs.push((cname, tensor_info(type_info)?));
+    }
+    Ok(signature)
+}
+
+// Reads and releases `type_info`.
+fn tensor_info(type_info: *mut sys::OrtTypeInfo) -> Result<TensorInfo> {
+    let api = get_api();
+    defer! {
+        unsafe { api.ReleaseTypeInfo.unwrap()(type_info); }
+    };
+
+    let mut tensor_shape: *const sys::OrtTensorTypeAndShapeInfo = std::ptr::null_mut();
+    ort_call!(api.CastTypeInfoToTensorInfo, type_info, &mut tensor_shape)?;
+
+    let mut elem_type: sys::ONNXTensorElementDataType =
+        sys::ONNXTensorElementDataType_ONNX_TENSOR_ELEMENT_DATA_TYPE_UNDEFINED;
+    ort_call!(api.GetTensorElementType, tensor_shape, &mut elem_type)?;
+
+    let mut dims = 0;
+    ort_call!(api.GetDimensionsCount, tensor_shape, &mut dims)?;
+
+    let mut shape: SmallVec<[i64; 4]> = smallvec![0; dims];
+    ort_call!(
+        api.GetDimensions,
+        tensor_shape,
+        shape.as_mut_ptr() as *mut _,
+        dims
+    )?;
+    let mut names: SmallVec<[*const i8; 4]> = smallvec![std::ptr::null(); dims];
+    ort_call!(
+        api.GetSymbolicDimensions,
+        tensor_shape,
+        names.as_mut_ptr(),
+        dims
+    )?;
+
+    Ok(TensorInfo {
+        elem_type: DataType::from(elem_type),
+        shape: shape
+            .iter()
+            .zip(names)
+            .map(|(&s, n)| {
+                if s >= 0 {
+                    Dim::Fixed(s as usize)
+                } else if n.is_null() {
+                    Dim::Symbolic(String::new())
+                } else {
+                    Dim::Symbolic(unsafe { CStr::from_ptr(n) }.to_string_lossy().into_owned())
+                }
+            })
+            .collect(),
+    })
+}
+
+// Wraps the array's memory in an OrtValue without copying.
+fn tensor_from_array<S, D>(data: &ndarray::ArrayBase<S, D>) -> Result<*mut sys::OrtValue>
+where
+    S: ndarray::RawData,
+    S::Elem: AsOnnxDataType,
+    D: ndarray::Dimension,
+{
+    check_standard_layout(data)?;
+    // ORT only reads inputs, despite the mutable pointer
+    tensor_from_ptr::<S::Elem>(data.as_ptr() as *mut _, data.shape())
+}
+
+// Wraps a buffer ORT writes into, so the pointer comes from the mutable borrow.
+fn tensor_from_array_mut<S, D>(data: &mut ndarray::ArrayBase<S, D>) -> Result<*mut sys::OrtValue>
+where
+    S: ndarray::DataMut,
+    S::Elem: AsOnnxDataType,
+    D: ndarray::Dimension,
+{
+    check_standard_layout(data)?;
+    let ptr = data.as_mut_ptr();
+    tensor_from_ptr::<S::Elem>(ptr, data.shape())
+}
+
+fn check_standard_layout<S, D>(data: &ndarray::ArrayBase<S, D>) -> Result<()>
+where
+    S: ndarray::RawData,
+    D: ndarray::Dimension,
+{
+    if !data.is_standard_layout() {
+        return Err(Error::InvalidInput(
+            "ndarray is not standard layout".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// The value borrows `ptr`, which must outlive it and hold `shape` elements.
+fn tensor_from_ptr<A: AsOnnxDataType>(ptr: *mut A, shape: &[usize]) -> Result<*mut sys::OrtValue> {
+    let len: usize = shape.iter().product();
+    let shape: smallvec::SmallVec<[_; 4]> = shape.iter().map(|s| *s as i64).collect();
+    let mut blob: *mut sys::OrtValue = std::ptr::null_mut();
+    ort_call!(
+        get_api().CreateTensorWithDataAsOrtValue,
+        get_cpu_mem_info(),
+        ptr as *mut _,
+        (len * std::mem::size_of::<A>()) as _,
+        shape.as_ptr(),
+        shape.len() as _,
+        A::as_onnx_data_type(),
+        &mut blob
+    )?;
+    Ok(blob)
+}
+
+impl From<Option<tsar::DataType>> for DataType {
+    fn from(dt: Option<tsar::DataType>) -> Self {
+        datatype_to_onnx(dt).into()
+    }
+}
+
 fn datatype_to_onnx(dt: Option<tsar::DataType>) -> sys::ONNXTensorElementDataType {
     match dt {
         None | Some(tsar::DataType::Byte) => {
@@ -658,7 +1161,26 @@ impl AsOnnxDataType for f64 {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+impl AsOnnxDataType for half::f16 {
+    fn as_onnx_data_type() -> sys::ONNXTensorElementDataType {
+        sys::ONNXTensorElementDataType_ONNX_TENSOR_ELEMENT_DATA_TYPE_FLOAT16
+    }
+}
+
+impl AsOnnxDataType for half::bf16 {
+    fn as_onnx_data_type() -> sys::ONNXTensorElementDataType {
+        sys::ONNXTensorElementDataType_ONNX_TENSOR_ELEMENT_DATA_TYPE_BFLOAT16
+    }
+}
+
+// ORT stores bool tensors one byte per element, like Rust
+impl AsOnnxDataType for bool {
+    fn as_onnx_data_type() -> sys::ONNXTensorElementDataType {
+        sys::ONNXTensorElementDataType_ONNX_TENSOR_ELEMENT_DATA_TYPE_BOOL
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum DataType {
     Unknown,
     Byte,
@@ -674,6 +1196,7 @@ pub enum DataType {
     Uint32,
     Int64,
     Uint64,
+    Bool,
 }
 
 impl From<sys::ONNXTensorElementDataType> for DataType {
@@ -700,19 +1223,20 @@ impl From<sys::ONNXTensorElementDataType> for DataType {
             sys::ONNXTensorElementDataType_ONNX_TENSOR_ELEMENT_DATA_TYPE_UINT32 => DataType::Uint32,
             sys::ONNXTensorElementDataType_ONNX_TENSOR_ELEMENT_DATA_TYPE_INT64 => DataType::Int64,
             sys::ONNXTensorElementDataType_ONNX_TENSOR_ELEMENT_DATA_TYPE_UINT64 => DataType::Uint64,
+            sys::ONNXTensorElementDataType_ONNX_TENSOR_ELEMENT_DATA_TYPE_BOOL => DataType::Bool,
             _ => DataType::Unknown,
         }
     }
 }
 
 #[cfg(unix)]
-fn path_to_cstring<P: AsRef<Path>>(path: P) -> CString {
+pub(super) fn path_to_cstring<P: AsRef<Path>>(path: P) -> CString {
     use std::os::unix::ffi::OsStrExt;
     CString::new(path.as_ref().as_os_str().as_bytes()).unwrap()
 }
 
 #[cfg(not(unix))]
-fn path_to_cstring<P: AsRef<Path>>(path: P) -> Vec<u16> {
+pub(super) fn path_to_cstring<P: AsRef<Path>>(path: P) -> Vec<u16> {
     use std::os::windows::ffi::OsStrExt;
 
     path.as_ref()
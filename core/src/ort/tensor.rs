@@ -0,0 +1,149 @@
+use std::ffi::c_void;
+
+use ort_sys as sys;
+use smallvec::SmallVec;
+
+use super::ort_call;
+use crate::{
+    ort::{get_api, session::AsOnnxDataType},
+    result::{Error, Result},
+};
+
+// A session output owned past the run that produced it. Outputs ORT allocated
+// keep their OrtValue until the tensor is dropped; outputs allocated with
+// `SessionRun::alloc_output` are plain arrays and convert without a copy.
+pub struct Tensor<A, D> {
+    repr: Repr<A, D>,
+}
+
+enum Repr<A, D> {
+    Ort {
+        // keeps `data` alive
+        _value: Value,
+        data: *mut A,
+        dim: D,
+    },
+    Owned(ndarray::Array<A, D>),
+}
+
+struct Value(*mut sys::OrtValue);
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        unsafe { get_api().ReleaseValue.unwrap()(self.0) };
+    }
+}
+
+unsafe impl<A: Send, D: Send> Send for Tensor<A, D> {}
+
+impl<A, D> Tensor<A, D>
+where
+    A: AsOnnxDataType,
+    D: ndarray::Dimension,
+{
+    // Takes ownership of `value`, which is released even if this fails.
+    pub(super) fn from_value(value: *mut sys::OrtValue) -> Result<Self> {
+        let value = Value(value);
+        let (data, dim) = tensor_parts(value.0)?;
+        Ok(Self {
+            repr: Repr::Ort {
+                _value: value,
+                data,
+                dim,
+            },
+        })
+    }
+
+    pub(super) fn from_array(array: ndarray::Array<A, D>) -> Self {
+        Self {
+            repr: Repr::Owned(array),
+        }
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        match &self.repr {
+            Repr::Ort { dim, .. } => dim.slice(),
+            Repr::Owned(a) => a.shape(),
+        }
+    }
+
+    pub fn view(&self) -> ndarray::ArrayView<'_, A, D> {
+        match &self.repr {
+            Repr::Ort { data, dim, .. } => unsafe {
+                ndarray::ArrayView::from_shape_ptr(dim.clone(), *data)
+            },
+            Repr::Owned(a) => a.view(),
+        }
+    }
+
+    pub fn view_mut(&mut self) -> ndarray::ArrayViewMut<'_, A, D> {
+        match &mut self.repr {
+            Repr::Ort { data, dim, .. } => unsafe {
+                ndarray::ArrayViewMut::from_shape_ptr(dim.clone(), *data)
+            },
+            Repr::Owned(a) => a.view_mut(),
+        }
+    }
+
+    // Free for outputs allocated by the caller, a copy otherwise.
+    pub fn into_array(self) -> ndarray::Array<A, D>
+    where
+        A: Clone,
+    {
+        match self.repr {
+            Repr::Owned(a) => a,
+            Repr::Ort { data, dim, .. } => {
+                unsafe { ndarray::ArrayView::from_shape_ptr(dim, data) }.to_owned()
+            }
+        }
+    }
+}
+
+// Data pointer and shape of a tensor value, checked against `A` and `D`.
+pub(super) fn tensor_parts<A, D>(value: *mut sys::OrtValue) -> Result<(*mut A, D)>
+where
+    A: AsOnnxDataType,
+    D: ndarray::Dimension,
+{
+    let api = get_api();
+    let mut data: *mut c_void = std::ptr::null_mut();
+    ort_call!(api.GetTensorMutableData, value, &mut data)?;
+
+    let mut info: *mut sys::OrtTensorTypeAndShapeInfo = std::ptr::null_mut();
+    ort_call!(api.GetTensorTypeAndShape, value, &mut info)?;
+    defer! {
+        unsafe { api.ReleaseTensorTypeAndShapeInfo.unwrap()(info); }
+    }
+
+    let mut dims = 0;
+    ort_call!(api.GetDimensionsCount, info, &mut dims)?;
+    if let Some(d) = D::NDIM {
+        if dims != d {
+            return Err(Error::InvalidInput(format!(
+                "invalid output dimension {}, expected {}",
+                dims, d
+            )));
+        }
+    }
+
+    let mut shape: SmallVec<[i64; 4]> = SmallVec::new();
+    shape.resize(dims, 0);
+    ort_call!(api.GetDimensions, info, shape.as_mut_ptr() as *mut _, dims)?;
+
+    let mut ty = sys::ONNXTensorElementDataType_ONNX_TENSOR_ELEMENT_DATA_TYPE_UNDEFINED;
+    ort_call!(api.GetTensorElementType, info, &mut ty)?;
+    if A::as_onnx_data_type() != ty {
+        return Err(Error::InvalidInput(format!(
+            "invalid output type {}, expected {}",
+            ty,
+            A::as_onnx_data_type()
+        )));
+    }
+
+    let mut d = D::zeros(shape.len());
+    let mut v = d.as_array_view_mut();
+    for (i, o) in shape.iter().enumerate() {
+        v[i] = *o as usize;
+    }
+    Ok((data as *mut A, d))
+}
@@ -0,0 +1,61 @@
+use super::DataType;
+use crate::result::{Error, Result};
+
+// Rewrites initializers before they are handed to the session, e.g. to merge
+// adapter weights. `data` holds the raw little endian tensor of `elem_type`.
+pub trait InitializerTransform: Send + Sync {
+    fn applies(&self, name: &str, shape: &[usize]) -> bool;
+    fn apply(
+        &self,
+        name: &str,
+        elem_type: DataType,
+        shape: &mut Vec<usize>,
+        data: &mut Vec<u8>,
+    ) -> Result<()>;
+
+    // Initializers the transform must reach. Only external initializers are
+    // transformed, so loading fails if one of these is missing or inline
+    // rather than leaving the weights unchanged.
+    fn required(&self) -> Vec<&str> {
+        vec![]
+    }
+}
+
+pub fn initializer_to_f32(elem_type: DataType, data: &[u8]) -> Result<Vec<f32>> {
+    match elem_type {
+        DataType::Float32 => Ok(data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()),
+        DataType::Float16 => Ok(data
+            .chunks_exact(2)
+            .map(|b| half::f16::from_le_bytes([b[0], b[1]]).to_f32())
+            .collect()),
+        DataType::Bfloat16 => Ok(data
+            .chunks_exact(2)
+            .map(|b| half::bf16::from_le_bytes([b[0], b[1]]).to_f32())
+            .collect()),
+        t => Err(Error::Unsupported(format!(
+            "initializer of type {:?} cannot be transformed",
+            t
+        ))),
+    }
+}
+
+pub fn initializer_from_f32(elem_type: DataType, data: &[f32]) -> Result<Vec<u8>> {
+    match elem_type {
+        DataType::Float32 => Ok(data.iter().flat_map(|v| v.to_le_bytes()).collect()),
+        DataType::Float16 => Ok(data
+            .iter()
+            .flat_map(|v| half::f16::from_f32(*v).to_le_bytes())
+            .collect()),
+        DataType::Bfloat16 => Ok(data
+            .iter()
+            .flat_map(|v| half::bf16::from_f32(*v).to_le_bytes())
+            .collect()),
+        t => Err(Error::Unsupported(format!(
+            "initializer of type {:?} cannot be transformed",
+            t
+        ))),
+    }
+}
@@ -0,0 +1,90 @@
+use std::{collections::HashMap, path::Path};
+
+use serde::Deserialize;
+
+use crate::result::{Error, Result};
+
+#[derive(Deserialize)]
+struct TensorInfo {
+    dtype: String,
+    shape: Vec<usize>,
+    data_offsets: (usize, usize),
+}
+
+// Reads every tensor of a safetensors file as f32.
+pub fn load(path: impl AsRef<Path>) -> Result<HashMap<String, ndarray::ArrayD<f32>>> {
+    parse(&std::fs::read(path)?)
+}
+
+pub fn parse(data: &[u8]) -> Result<HashMap<String, ndarray::ArrayD<f32>>> {
+    let invalid = || Error::InvalidInput("invalid safetensors file".to_string());
+    let len = u64::from_le_bytes(data.get(..8).ok_or_else(invalid)?.try_into().unwrap());
+    // the length comes from the file, so it may be anything
+    let end = usize::try_from(len)
+        .ok()
+        .and_then(|len| 8usize.checked_add(len))
+        .ok_or_else(invalid)?;
+    let header = data.get(8..end).ok_or_else(invalid)?;
+    let body = &data[end..];
+
+    let mut header: HashMap<String, serde_json::Value> = serde_json::from_slice(header)?;
+    header.remove("__metadata__");
+
+    header
+        .into_iter()
+        .map(|(name, info)| {
+            let info: TensorInfo = serde_json::from_value(info)?;
+            let (start, end) = info.data_offsets;
+            let raw = body.get(start..end).ok_or_else(invalid)?;
+            let values: Vec<f32> = match info.dtype.as_str() {
+                "F32" => raw
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect(),
+                "F16" => raw
+                    .chunks_exact(2)
+                    .map(|b| half::f16::from_le_bytes([b[0], b[1]]).to_f32())
+                    .collect(),
+                "BF16" => raw
+                    .chunks_exact(2)
+                    .map(|b| half::bf16::from_le_bytes([b[0], b[1]]).to_f32())
+                    .collect(),
+                t => {
+                    return Err(Error::Unsupported(format!(
+                        "safetensors dtype {} of {}",
+                        t, name
+                    )))
+                }
+            };
+            let tensor = ndarray::ArrayD::from_shape_vec(info.shape, values)
+                .map_err(|_| Error::InvalidInput(format!("invalid shape for {}", name)))?;
+            Ok((name, tensor))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let header =
+            br#"{"__metadata__":{"a":"b"},"w":{"dtype":"F16","shape":[2],"data_offsets":[0,4]}}"#;
+        let mut data = (header.len() as u64).to_le_bytes().to_vec();
+        data.extend_from_slice(header);
+        data.extend(half::f16::from_f32(1.5).to_le_bytes());
+        data.extend(half::f16::from_f32(-2.).to_le_bytes());
+
+        let t = parse(&data).unwrap();
+        assert_eq!(t.len(), 1);
+        assert_eq!(t["w"].as_slice().unwrap(), &[1.5, -2.]);
+    }
+
+    #[test]
+    fn test_parse_header_length() {
+        let mut data = u64::MAX.to_le_bytes().to_vec();
+        data.extend_from_slice(b"{}");
+        assert!(matches!(parse(&data), Err(Error::InvalidInput(_))));
+    }
+}
@@ -3,15 +3,23 @@ use std::{
     iter,
 };
 
+use ndarray::{Axis, Slice};
+use ndarray_rand::rand::{thread_rng, Rng};
 use quad_rs::prelude::*;
 
-use crate::model::{Diffusion, DiffusionScheduleParam};
+use crate::{
+    model::{Control, Diffusion, DiffusionScheduleParam, Model},
+    result::Result,
+    tile,
+};
 
 pub struct DdimSampler<'a> {
     pub model: &'a mut dyn Diffusion,
     pub steps: &'a Vec<DiffusionScheduleParam>,
     pub c: HashMap<String, ndarray::ArrayD<f32>>,
     pub seed: ndarray::ArrayD<f32>,
+    // model output, reused across steps
+    out: ndarray::ArrayD<f32>,
 }
 
 impl<'a> DdimSampler<'a> {
@@ -42,6 +50,7 @@ impl<'a> DdimSampler<'a> {
             steps,
             c,
             seed,
+            out: ndarray::ArrayD::zeros([].as_slice()),
         }
     }
 
@@ -61,7 +70,10 @@ impl<'a> DdimSampler<'a> {
 
         let seed =
             ndarray::concatenate(ndarray::Axis(0), &[self.seed.view(), self.seed.view()]).unwrap();
-        let seed = self.model.execute(&seed, t, &self.c).unwrap();
+        self.model
+            .execute_into(&seed, t, &self.c, &mut self.out)
+            .unwrap();
+        let seed = &self.out;
         let mut e_t = ndarray::ArrayD::<f32>::zeros(self.seed.shape());
         for i in 0..batch {
             e_t.index_axis_mut(ndarray::Axis(0), i).assign(
@@ -86,6 +98,10 @@ pub struct LmsSampler<'a> {
     pub c: HashMap<String, ndarray::ArrayD<f32>>,
     pub seed: ndarray::ArrayD<f32>,
     pub derivatives: VecDeque<ndarray::ArrayD<f32>>,
+    // how far the prediction moves from the unconditional one toward the conditional
+    pub guidance_scale: f32,
+    // conditional and unconditional model outputs, reused across steps
+    out: [ndarray::ArrayD<f32>; 2],
 }
 
 impl<'a> LmsSampler<'a> {
@@ -117,6 +133,11 @@ impl<'a> LmsSampler<'a> {
             c,
             seed,
             derivatives: VecDeque::new(),
+            guidance_scale: 7.5,
+            out: [
+                ndarray::ArrayD::zeros([].as_slice()),
+                ndarray::ArrayD::zeros([].as_slice()),
+            ],
         }
     }
 
@@ -141,7 +162,9 @@ impl<'a> LmsSampler<'a> {
                             .to_owned(),
                     );
                 }
-                let vi = self.model.execute(&s, t, &c).unwrap();
+                self.model
+                    .execute_into(&s, t, &c, &mut self.out[0])
+                    .unwrap();
 
                 for (k, v) in self.c.iter() {
                     c.insert(
@@ -151,10 +174,14 @@ impl<'a> LmsSampler<'a> {
                             .to_owned(),
                     );
                 }
-                let vib = self.model.execute(&s, t, &c).unwrap();
+                self.model
+                    .execute_into(&s, t, &c, &mut self.out[1])
+                    .unwrap();
 
-                e_t.index_axis_mut(ndarray::Axis(0), i)
-                    .assign(&(vib.to_owned() + (vi - vib) * 7.5).index_axis(ndarray::Axis(0), 0));
+                let [vi, vib] = &self.out;
+                e_t.index_axis_mut(ndarray::Axis(0), i).assign(
+                    &(vib + &((vi - vib) * self.guidance_scale)).index_axis(ndarray::Axis(0), 0),
+                );
             }
             e_t
         } else {
@@ -168,7 +195,7 @@ impl<'a> LmsSampler<'a> {
                     &(seed.index_axis(ndarray::Axis(0), i + batch).to_owned()
                         + (seed.index_axis(ndarray::Axis(0), i).to_owned()
                             - seed.index_axis(ndarray::Axis(0), i + batch))
-                            * 7.5),
+                            * self.guidance_scale),
                 );
             }
             e_t
@@ -246,3 +273,203 @@ impl<'a> LmsSampler<'a> {
             .unwrap()
     }
 }
+
+// applies `f` to conditions that are spatially aligned with the latent, e.g. depth maps,
+// or with the image like control hints, passing their scale relative to the latent
+fn map_spatial<F>(
+    conditions: &HashMap<String, ndarray::ArrayD<f32>>,
+    h: usize,
+    w: usize,
+    f: F,
+) -> HashMap<String, ndarray::ArrayD<f32>>
+where
+    F: Fn(&ndarray::ArrayD<f32>, usize) -> ndarray::ArrayD<f32>,
+{
+    conditions
+        .iter()
+        .map(|(k, v)| {
+            let (vh, vw) = (v.shape().get(2), v.shape().get(3));
+            match (vh, vw) {
+                (Some(&vh), Some(&vw))
+                    if v.ndim() == 4 && vh % h == 0 && vw % w == 0 && vh / h == vw / w =>
+                {
+                    (k.clone(), f(v, vh / h))
+                }
+                _ => (k.clone(), v.clone()),
+            }
+        })
+        .collect()
+}
+
+// Adds the control hints of `model` to the conditions, unless a sampler
+// around this one already did.
+fn with_control_hints(
+    model: &dyn Diffusion,
+    conditions: &HashMap<String, ndarray::ArrayD<f32>>,
+    h: usize,
+    w: usize,
+) -> HashMap<String, ndarray::ArrayD<f32>> {
+    let mut conditions = conditions.clone();
+    for (k, v) in model.control_conditions(h, w) {
+        conditions.entry(k).or_insert(v);
+    }
+    conditions
+}
+
+// https://arxiv.org/abs/2302.08113
+pub struct MultiDiffusion<'a> {
+    pub model: &'a mut dyn Diffusion,
+    pub window: usize,
+    pub stride: usize,
+}
+
+impl<'a> MultiDiffusion<'a> {
+    pub fn new(model: &'a mut dyn Diffusion, window: usize, stride: usize) -> Self {
+        Self {
+            model,
+            window,
+            stride: stride.clamp(1, window),
+        }
+    }
+}
+
+impl Model for MultiDiffusion<'_> {
+    fn unload_model(&mut self) {
+        self.model.unload_model();
+    }
+}
+
+impl Diffusion for MultiDiffusion<'_> {
+    fn make_schedule(&self, num_steps: usize) -> Vec<DiffusionScheduleParam> {
+        self.model.make_schedule(num_steps)
+    }
+
+    fn make_noise(&self, b: usize, w: usize, h: usize) -> ndarray::ArrayD<f32> {
+        self.model.make_noise(b, w, h)
+    }
+
+    fn image_scale(&self) -> usize {
+        self.model.image_scale()
+    }
+
+    fn execute(
+        &mut self,
+        x: &ndarray::ArrayD<f32>,
+        t: &DiffusionScheduleParam,
+        conditions: &HashMap<String, ndarray::ArrayD<f32>>,
+    ) -> Result<ndarray::ArrayD<f32>> {
+        if x.is_empty() {
+            return Ok(ndarray::ArrayD::zeros([].as_slice()));
+        }
+
+        let (h, w) = (x.shape()[2], x.shape()[3]);
+        let overlap = self.window - self.stride;
+        let rows = tile::tile_ranges(h, self.window, overlap, 1);
+        let cols = tile::tile_ranges(w, self.window, overlap, 1);
+        if rows.len() == 1 && cols.len() == 1 {
+            return self.model.execute(x, t, conditions);
+        }
+
+        let conditions = with_control_hints(&*self.model, conditions, h, w);
+        let mut sum = ndarray::ArrayD::<f32>::zeros(x.shape());
+        let mut count = ndarray::ArrayD::<f32>::zeros([1, 1, h, w].as_slice());
+        for r in rows.iter() {
+            for c in cols.iter() {
+                let window = x
+                    .slice_axis(Axis(2), Slice::from(r.clone()))
+                    .slice_axis(Axis(3), Slice::from(c.clone()))
+                    .as_standard_layout()
+                    .to_owned();
+                let cond = map_spatial(&conditions, h, w, |v, s| {
+                    v.slice_axis(Axis(2), Slice::from(r.start * s..r.end * s))
+                        .slice_axis(Axis(3), Slice::from(c.start * s..c.end * s))
+                        .as_standard_layout()
+                        .to_owned()
+                });
+                let y = self.model.execute(&window, t, &cond)?;
+
+                sum.slice_axis_mut(Axis(2), Slice::from(r.clone()))
+                    .slice_axis_mut(Axis(3), Slice::from(c.clone()))
+                    .zip_mut_with(&y, |a, v| *a += v);
+                count
+                    .slice_axis_mut(Axis(2), Slice::from(r.clone()))
+                    .slice_axis_mut(Axis(3), Slice::from(c.clone()))
+                    .map_inplace(|v| *v += 1.);
+            }
+        }
+        Ok(sum / count)
+    }
+
+    fn controls_mut(&mut self) -> Option<&mut Vec<Control>> {
+        self.model.controls_mut()
+    }
+
+    fn control_conditions(&self, h: usize, w: usize) -> HashMap<String, ndarray::ArrayD<f32>> {
+        self.model.control_conditions(h, w)
+    }
+}
+
+// Rolls the latent by a random offset around every step so the wrap-around
+// seam ends up in a different place each time and gets denoised away.
+pub struct SeamlessDiffusion<'a> {
+    pub model: &'a mut dyn Diffusion,
+}
+
+impl<'a> SeamlessDiffusion<'a> {
+    pub fn new(model: &'a mut dyn Diffusion) -> Self {
+        Self { model }
+    }
+}
+
+impl Model for SeamlessDiffusion<'_> {
+    fn unload_model(&mut self) {
+        self.model.unload_model();
+    }
+}
+
+impl Diffusion for SeamlessDiffusion<'_> {
+    fn make_schedule(&self, num_steps: usize) -> Vec<DiffusionScheduleParam> {
+        self.model.make_schedule(num_steps)
+    }
+
+    fn make_noise(&self, b: usize, w: usize, h: usize) -> ndarray::ArrayD<f32> {
+        self.model.make_noise(b, w, h)
+    }
+
+    fn image_scale(&self) -> usize {
+        self.model.image_scale()
+    }
+
+    fn execute(
+        &mut self,
+        x: &ndarray::ArrayD<f32>,
+        t: &DiffusionScheduleParam,
+        conditions: &HashMap<String, ndarray::ArrayD<f32>>,
+    ) -> Result<ndarray::ArrayD<f32>> {
+        if x.is_empty() {
+            return Ok(ndarray::ArrayD::zeros([].as_slice()));
+        }
+
+        let mut rng = thread_rng();
+        let dy = rng.gen_range(0..x.shape()[2]) as isize;
+        let dx = rng.gen_range(0..x.shape()[3]) as isize;
+
+        let (h, w) = (x.shape()[2], x.shape()[3]);
+        let x = tile::roll(&tile::roll(x, 2, dy), 3, dx);
+        let conditions = with_control_hints(&*self.model, conditions, h, w);
+        let cond = map_spatial(&conditions, h, w, |v, s| {
+            let s = s as isize;
+            tile::roll(&tile::roll(v, 2, dy * s), 3, dx * s)
+        });
+        let y = self.model.execute(&x, t, &cond)?;
+        Ok(tile::roll(&tile::roll(&y, 2, -dy), 3, -dx))
+    }
+
+    fn controls_mut(&mut self) -> Option<&mut Vec<Control>> {
+        self.model.controls_mut()
+    }
+
+    fn control_conditions(&self, h: usize, w: usize) -> HashMap<String, ndarray::ArrayD<f32>> {
+        self.model.control_conditions(h, w)
+    }
+}
@@ -0,0 +1,230 @@
+use std::ops::Range;
+
+use ndarray::{Axis, Slice};
+
+use crate::result::{Error, Result};
+
+#[derive(Clone, Copy, Debug)]
+pub struct TileConfig {
+    pub tile_size: usize,
+    pub overlap: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Tiling {
+    #[default]
+    Disabled,
+    Enabled(TileConfig),
+    // tile only when the image is larger than `max_pixels`
+    Auto {
+        max_pixels: usize,
+        tile: TileConfig,
+    },
+}
+
+impl Tiling {
+    pub fn select(&self, pixels: usize) -> Option<TileConfig> {
+        match *self {
+            Tiling::Disabled => None,
+            Tiling::Enabled(t) => Some(t),
+            Tiling::Auto { max_pixels, tile } if pixels > max_pixels => Some(tile),
+            Tiling::Auto { .. } => None,
+        }
+    }
+}
+
+pub fn tile_ranges(len: usize, size: usize, overlap: usize, align: usize) -> Vec<Range<usize>> {
+    let size = (size / align * align).max(align);
+    if len <= size {
+        return vec![0..len];
+    }
+
+    let stride = (size.saturating_sub(overlap) / align * align).max(align);
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    loop {
+        if start + size >= len {
+            ranges.push((len - size) / align * align..len);
+            break;
+        }
+        ranges.push(start..start + size);
+        start += stride;
+    }
+    ranges
+}
+
+fn feather(len: usize, head: usize, tail: usize) -> ndarray::Array1<f32> {
+    let head = head.min(len / 2);
+    let tail = tail.min(len / 2);
+    ndarray::Array1::from_shape_fn(len, |i| {
+        let mut w: f32 = 1.0;
+        if head > 0 {
+            w = w.min((i + 1) as f32 / (head + 1) as f32);
+        }
+        if tail > 0 {
+            w = w.min((len - i) as f32 / (tail + 1) as f32);
+        }
+        w
+    })
+}
+
+// Runs `f` over overlapping NCHW tiles of `x` and linearly blends the results.
+// The output scale is inferred from the first tile, so `f` may resize its input.
+pub fn execute_tiled<F, P>(
+    x: &ndarray::ArrayD<f32>,
+    tile: &TileConfig,
+    align: usize,
+    mut f: F,
+    mut progress: P,
+) -> Result<ndarray::ArrayD<f32>>
+where
+    F: FnMut(&ndarray::ArrayD<f32>) -> Result<ndarray::ArrayD<f32>>,
+    P: FnMut(usize, usize),
+{
+    if x.ndim() != 4 {
+        return Err(Error::InvalidInput(format!(
+            "tiled input must be NCHW, got {} dimensions",
+            x.ndim()
+        )));
+    }
+    let (h, w) = (x.shape()[2], x.shape()[3]);
+    let rows = tile_ranges(h, tile.tile_size, tile.overlap, align);
+    let cols = tile_ranges(w, tile.tile_size, tile.overlap, align);
+    let total = rows.len() * cols.len();
+
+    let mut out: Option<(ndarray::ArrayD<f32>, ndarray::ArrayD<f32>, f64)> = None;
+    for (i, (r, c)) in rows
+        .iter()
+        .flat_map(|r| cols.iter().map(move |c| (r, c)))
+        .enumerate()
+    {
+        progress(i, total);
+        let t = x
+            .slice_axis(Axis(2), Slice::from(r.clone()))
+            .slice_axis(Axis(3), Slice::from(c.clone()))
+            .as_standard_layout()
+            .to_owned();
+        let y = f(&t)?;
+
+        let (acc, weight, scale) = out.get_or_insert_with(|| {
+            let scale = y.shape()[2] as f64 / r.len() as f64;
+            let oh = (h as f64 * scale).round() as usize;
+            let ow = (w as f64 * scale).round() as usize;
+            (
+                ndarray::ArrayD::zeros([x.shape()[0], y.shape()[1], oh, ow].as_slice()),
+                ndarray::ArrayD::zeros([1, 1, oh, ow].as_slice()),
+                scale,
+            )
+        });
+        let scale = *scale;
+        let s = |p: usize| (p as f64 * scale).round() as usize;
+        let (oy, ox) = (s(r.start), s(c.start));
+        let (th, tw) = (y.shape()[2], y.shape()[3]);
+        if oy + th > acc.shape()[2] || ox + tw > acc.shape()[3] {
+            return Err(Error::InvalidInput(format!(
+                "tile output {}x{} does not match the inferred scale {}",
+                th, tw, scale
+            )));
+        }
+
+        let wy = feather(
+            th,
+            if r.start > 0 { s(tile.overlap) } else { 0 },
+            if r.end < h { s(tile.overlap) } else { 0 },
+        );
+        let wx = feather(
+            tw,
+            if c.start > 0 { s(tile.overlap) } else { 0 },
+            if c.end < w { s(tile.overlap) } else { 0 },
+        );
+        let wt = (wy.insert_axis(Axis(1)) * wx.insert_axis(Axis(0)))
+            .into_dyn()
+            .insert_axis(Axis(0))
+            .insert_axis(Axis(0));
+
+        acc.slice_axis_mut(Axis(2), Slice::from(oy..oy + th))
+            .slice_axis_mut(Axis(3), Slice::from(ox..ox + tw))
+            .zip_mut_with(&(&y * &wt), |a, v| *a += v);
+        weight
+            .slice_axis_mut(Axis(2), Slice::from(oy..oy + th))
+            .slice_axis_mut(Axis(3), Slice::from(ox..ox + tw))
+            .zip_mut_with(&wt, |a, v| *a += v);
+    }
+    progress(total, total);
+
+    let (acc, weight, _) = out.ok_or(Error::Unknown)?;
+    Ok(acc / weight)
+}
+
+pub fn roll(x: &ndarray::ArrayD<f32>, axis: usize, shift: isize) -> ndarray::ArrayD<f32> {
+    let n = x.shape()[axis];
+    let s = if n == 0 {
+        0
+    } else {
+        shift.rem_euclid(n as isize) as usize
+    };
+    if s == 0 {
+        return x.clone();
+    }
+    ndarray::concatenate(
+        Axis(axis),
+        &[
+            x.slice_axis(Axis(axis), Slice::from(n - s..)),
+            x.slice_axis(Axis(axis), Slice::from(..n - s)),
+        ],
+    )
+    .unwrap()
+}
+
+// Pads the spatial axes of a NCHW tensor with wrap-around content.
+pub fn pad_wrap(x: &ndarray::ArrayD<f32>, pad: usize) -> ndarray::ArrayD<f32> {
+    let mut y = x.clone();
+    for axis in [2, 3] {
+        let n = y.shape()[axis];
+        let p = pad.min(n);
+        y = ndarray::concatenate(
+            Axis(axis),
+            &[
+                y.slice_axis(Axis(axis), Slice::from(n - p..)),
+                y.view(),
+                y.slice_axis(Axis(axis), Slice::from(..p)),
+            ],
+        )
+        .unwrap();
+    }
+    y
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tile_ranges() {
+        assert_eq!(tile_ranges(64, 128, 16, 8), vec![0..64]);
+        assert_eq!(tile_ranges(160, 64, 16, 8), vec![0..64, 48..112, 96..160]);
+        assert_eq!(tile_ranges(100, 64, 16, 1), vec![0..64, 36..100]);
+    }
+
+    #[test]
+    fn test_execute_tiled_identity() {
+        let x = ndarray::ArrayD::from_shape_fn([1, 3, 40, 56].as_slice(), |d| {
+            (d[1] * 1000 + d[2] * 10 + d[3]) as f32
+        });
+        let tile = TileConfig {
+            tile_size: 16,
+            overlap: 4,
+        };
+        let y = execute_tiled(&x, &tile, 1, |t| Ok(t.clone()), |_, _| {}).unwrap();
+        assert!((&x - &y).iter().all(|v| v.abs() < 1e-3));
+    }
+
+    #[test]
+    fn test_roll() {
+        let x =
+            ndarray::ArrayD::from_shape_vec([1, 1, 1, 4].as_slice(), vec![0., 1., 2., 3.]).unwrap();
+        assert_eq!(roll(&x, 3, 1).as_slice().unwrap(), &[3., 0., 1., 2.]);
+        assert_eq!(roll(&roll(&x, 3, -5), 3, 5), x);
+        assert_eq!(pad_wrap(&x, 1).shape(), &[1, 1, 3, 6]);
+    }
+}